@@ -7,6 +7,7 @@ use std::{
 use clap::{Parser, Subcommand, ValueEnum};
 use error::AssemblerError;
 use instruction::Instruction;
+use nes_lib::cartridge::Cartridge;
 use parser::Line;
 use tracing::{debug, metadata::LevelFilter};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
@@ -20,6 +21,10 @@ mod parser;
 mod process;
 mod validation;
 
+const PRG_ROM_SIZE: usize = 0x4000;
+// NMI, reset and IRQ/BRK vectors at $FFFA-$FFFF
+const VECTORS: usize = PRG_ROM_SIZE - 6;
+
 #[derive(Parser)]
 #[command(
     author = "Kyle Gagnon",
@@ -49,7 +54,7 @@ enum Commands {
     Assemble,
     /// Writes the assembly file to a JSON format
     Json,
-    /// Generates the full ROM for the NES ROM
+    /// Generates a full iNES ROM on an NROM board
     NES,
 }
 
@@ -146,29 +151,25 @@ pub fn to_bytes(lines: Vec<Instruction>) -> Result<Vec<u8>, AssemblerError> {
     Ok(bytes)
 }
 
-fn add_padding(mut bytes: Vec<u8>, start_pos: u16) -> Result<Vec<u8>, AssemblerError> {
-    // Ensure the assembled program doesn't exceed the PRG ROM size
-    if bytes.len() > 0xFFC {
+fn build_prg_rom(bytes: Vec<u8>, start_pos: u16) -> Result<Vec<u8>, AssemblerError> {
+    // The program sits wherever its ORG lands in a 16KiB NROM-128 bank,
+    // which the board mirrors into both $8000 and $C000
+    let offset = start_pos as usize % PRG_ROM_SIZE;
+
+    // Ensure the assembled program doesn't run into the interrupt vectors
+    if offset + bytes.len() > VECTORS {
         return Err(AssemblerError::ProgramTooLarge);
     }
 
-    // Add padding to reach the reset vector
-    let padding = vec![0; 0xFFC - bytes.len()];
-    bytes.extend(padding);
-
-    // Split the start_pos into low and high bytes
-    let hi = (start_pos >> 8) as u8;
-    let lo = (start_pos & 0xff) as u8;
-
-    // Write the reset vector
-    bytes.push(lo);
-    bytes.push(hi);
+    let mut prg_rom = vec![0; PRG_ROM_SIZE];
+    prg_rom[offset..(offset + bytes.len())].copy_from_slice(&bytes);
 
-    // Add the reset of the interrupt vectors (NMI, IRQ/BRK)
-    // Set to zero for now, adjust when needed
-    bytes.extend(vec![0; 4]);
+    // Write the reset vector. NMI and IRQ/BRK are left at zero for now,
+    // adjust when needed
+    prg_rom[VECTORS + 2] = (start_pos & 0xff) as u8;
+    prg_rom[VECTORS + 3] = (start_pos >> 8) as u8;
 
-    Ok(bytes)
+    Ok(prg_rom)
 }
 
 fn write_bytes_to_file(file: &PathBuf, bytes: &Vec<u8>) -> Result<(), AssemblerError> {
@@ -257,18 +258,22 @@ fn nes(lines: Vec<(Line, usize)>, args: &Cli) {
         }
     };
 
-    let padded_bytes = match add_padding(bytes, start_pos as u16) {
-        Ok(bytes) => bytes,
+    let prg_rom = match build_prg_rom(bytes, start_pos as u16) {
+        Ok(prg_rom) => prg_rom,
         Err(e) => {
             eprintln!("{}: error - {}", args.input.to_str().unwrap(), e);
             std::process::exit(1);
         }
     };
 
-    match write_bytes_to_file(&args.output, &padded_bytes) {
+    // An NROM cartridge with CHR RAM, so the emulator boots it like any
+    // other iNES file
+    let rom = Cartridge::new(0, prg_rom, vec![]).to_ines();
+
+    match write_bytes_to_file(&args.output, &rom) {
         Ok(_) => println!(
             "Assembled {} bytes to {}",
-            padded_bytes.len(),
+            rom.len(),
             &args.output.to_string_lossy()
         ),
         Err(e) => {
@@ -277,3 +282,27 @@ fn nes(lines: Vec<(Line, usize)>, args: &Cli) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_prg_rom_places_program_at_org() {
+        let prg_rom = build_prg_rom(vec![0xA9, 0x01], 0xC010).unwrap();
+        assert_eq!(prg_rom.len(), 0x4000);
+        assert_eq!(&prg_rom[0x10..0x12], &[0xA9, 0x01]);
+        assert_eq!(&prg_rom[0x3FFC..0x3FFE], &[0x10, 0xC0]);
+
+        let cartridge = Cartridge::from_bytes(&Cartridge::new(0, prg_rom, vec![]).to_ines());
+        assert_eq!(cartridge.unwrap().mapper, 0);
+    }
+
+    #[test]
+    fn test_build_prg_rom_rejects_program_over_vectors() {
+        assert_eq!(
+            build_prg_rom(vec![0xEA; 0x3FFB], 0x8000),
+            Err(AssemblerError::ProgramTooLarge)
+        );
+    }
+}
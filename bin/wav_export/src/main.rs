@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use nes_lib::{apu::Recording, cpu::CPU};
+use tracing::{info, metadata::LevelFilter};
+use tracing_subscriber::{EnvFilter, FmtSubscriber};
+
+#[derive(Parser)]
+#[command(
+    author = "Kyle Gagnon",
+    version = "0.1.0",
+    about = "Runs a ROM with no window and writes its audio to a .wav file."
+)]
+struct Cli {
+    /// The iNES ROM to run; Required
+    #[arg(short, long, value_name = "ROM")]
+    rom: PathBuf,
+
+    /// The .wav file to write
+    #[arg(short, long, value_name = "OUTPUT")]
+    output: PathBuf,
+
+    /// How many video frames to run for, 60 to a second
+    #[arg(short, long, default_value_t = 600)]
+    frames: u64,
+
+    /// Output samples per second
+    #[arg(
+        short,
+        long,
+        default_value_t = nes_lib::apu::DEFAULT_SAMPLE_RATE,
+        value_parser = clap::value_parser!(u32).range(1..=nes_lib::apu::MAX_SAMPLE_RATE as i64)
+    )]
+    sample_rate: u32,
+
+    /// Also write each channel to its own file next to the output
+    #[arg(long)]
+    stems: bool,
+
+    /// The level of verbosity to use
+    #[arg(short, long)]
+    verbose: Option<VerboseLevels>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum VerboseLevels {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+pub fn main() {
+    let cli = Cli::parse();
+
+    let level = match cli.verbose {
+        Some(VerboseLevels::Trace) => LevelFilter::TRACE,
+        Some(VerboseLevels::Debug) => LevelFilter::DEBUG,
+        Some(VerboseLevels::Info) => LevelFilter::INFO,
+        Some(VerboseLevels::Warn) => LevelFilter::WARN,
+        Some(VerboseLevels::Error) => LevelFilter::ERROR,
+        None => LevelFilter::OFF,
+    };
+
+    let filter = EnvFilter::from_default_env().add_directive(level.into());
+
+    let subscriber = FmtSubscriber::builder().with_env_filter(filter).finish();
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let mut cpu = match CPU::from_rom_file(&cli.rom) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("{}: error - {}", cli.rom.display(), e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = cpu.bus.apu_mut().set_sample_rate(cli.sample_rate) {
+        eprintln!("error - {}", e);
+        std::process::exit(1);
+    }
+
+    let recording = match Recording::capture(&mut cpu, cli.frames, cli.stems) {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("{}: error - {}", cli.rom.display(), e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Recorded {} samples at {}Hz",
+        recording.mix.len(),
+        recording.sample_rate
+    );
+
+    if let Err(e) = recording.write(&cli.output) {
+        eprintln!("{}: error - {}", cli.output.display(), e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,317 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use lazy_static::lazy_static;
+
+use super::{Channel, ChannelOutputs};
+
+/// The NTSC CPU clock, which the APU runs from
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+
+// Taps in the band-limited step and how finely the step's position between
+// two output samples is resolved
+const TAPS: usize = 16;
+const PHASES: usize = 64;
+// Where the kernel cuts off, as a fraction of the output Nyquist frequency
+const CUTOFF: f64 = 0.9;
+// The console's output stage high-passes at about 90Hz, which also takes
+// out the mixer's DC offset
+const HIGH_PASS_HZ: f64 = 90.0;
+
+lazy_static! {
+    // A windowed sinc impulse for each phase, normalized so a step of 1 in
+    // the input adds up to exactly 1 in the output
+    static ref KERNEL: Vec<[f32; TAPS]> = (0..PHASES)
+        .map(|phase| {
+            let offset = phase as f64 / PHASES as f64;
+            let mut taps = [0.0; TAPS];
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = (k + 1) as f64 - offset - (TAPS / 2) as f64;
+                let window = 0.42
+                    + 0.5 * (2.0 * PI * x / TAPS as f64).cos()
+                    + 0.08 * (4.0 * PI * x / TAPS as f64).cos();
+                *tap = sinc(CUTOFF * x) * window;
+            }
+            let sum: f64 = taps.iter().sum();
+            taps.map(|tap| (tap / sum) as f32)
+        })
+        .collect();
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl ChannelOutputs {
+    /// Mixes the channels with the console's non-linear DAC formulas,
+    /// giving a level between 0 and 1
+    pub fn mix(&self) -> f32 {
+        let pulse = (self.pulse_1 + self.pulse_2) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+
+        let tnd =
+            self.triangle as f32 / 8227.0 + self.noise as f32 / 12241.0 + self.dmc as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    /// Just the one channel, with the rest silent
+    pub fn only(&self, channel: Channel) -> ChannelOutputs {
+        let mut outputs = ChannelOutputs::default();
+        match channel {
+            Channel::Pulse1 => outputs.pulse_1 = self.pulse_1,
+            Channel::Pulse2 => outputs.pulse_2 = self.pulse_2,
+            Channel::Triangle => outputs.triangle = self.triangle,
+            Channel::Noise => outputs.noise = self.noise,
+            Channel::Dmc => outputs.dmc = self.dmc,
+        }
+        outputs
+    }
+}
+
+/// Mixed samples waiting for the host to pick them up, oldest first. If the
+/// host falls behind the oldest samples are dropped to make room.
+#[derive(Debug)]
+pub struct SampleBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl SampleBuffer {
+    pub fn new(capacity: usize) -> Self {
+        SampleBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn push(&mut self, sample: f32) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Moves as many samples as fit into `out` and returns how many that was
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = sample;
+        }
+        count
+    }
+
+    /// Takes every sample that is waiting
+    pub fn take_all(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
+
+/// Turns a signal clocked at the CPU rate into samples at the output rate.
+/// Every change in the input is added as a band-limited step rather than
+/// point sampled, so the square waves don't alias.
+#[derive(Debug)]
+pub struct Resampler {
+    // Output samples per CPU cycle
+    ratio: f64,
+    // How far into the current output sample we are, from 0 to 1
+    time: f64,
+    // The changes still to land in each of the next output samples
+    deltas: VecDeque<f32>,
+    level: f32,
+    last_input: f32,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        Resampler {
+            ratio: sample_rate as f64 / CPU_CLOCK_RATE,
+            time: 0.0,
+            deltas: VecDeque::from(vec![0.0; TAPS]),
+            level: 0.0,
+            last_input: 0.0,
+        }
+    }
+
+    /// Takes the input for one CPU cycle, returning a sample whenever one is
+    /// finished
+    pub fn clock(&mut self, input: f32) -> Option<f32> {
+        let delta = input - self.last_input;
+        if delta != 0.0 {
+            self.last_input = input;
+            let phase = (self.time * PHASES as f64) as usize;
+            for (slot, weight) in self.deltas.iter_mut().zip(KERNEL[phase]) {
+                *slot += delta * weight;
+            }
+        }
+
+        self.time += self.ratio;
+        if self.time < 1.0 {
+            return None;
+        }
+        self.time -= 1.0;
+        self.level += self.deltas.pop_front().unwrap_or(0.0);
+        self.deltas.push_back(0.0);
+        Some(self.level)
+    }
+}
+
+/// Everything between a mixed level at the CPU rate and samples the host
+/// can play
+#[derive(Debug)]
+pub struct SampleStream {
+    resampler: Resampler,
+    high_pass: HighPass,
+    pub samples: SampleBuffer,
+}
+
+impl SampleStream {
+    pub fn new(sample_rate: u32) -> Self {
+        SampleStream {
+            resampler: Resampler::new(sample_rate),
+            high_pass: HighPass::new(sample_rate),
+            // Keep up to a second of audio for hosts that pull in big chunks
+            samples: SampleBuffer::new(sample_rate as usize),
+        }
+    }
+
+    pub fn clock(&mut self, level: f32) {
+        if let Some(sample) = self.resampler.clock(level) {
+            self.samples.push(self.high_pass.filter(sample));
+        }
+    }
+}
+
+/// A first order high-pass filter
+#[derive(Debug)]
+pub struct HighPass {
+    alpha: f32,
+    last_input: f32,
+    last_output: f32,
+}
+
+impl HighPass {
+    pub fn new(sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * HIGH_PASS_HZ);
+        let dt = 1.0 / sample_rate as f64;
+        HighPass {
+            alpha: (rc / (rc + dt)) as f32,
+            last_input: 0.0,
+            last_output: 0.0,
+        }
+    }
+
+    pub fn filter(&mut self, input: f32) -> f32 {
+        self.last_output = self.alpha * (self.last_output + input - self.last_input);
+        self.last_input = input;
+        self.last_output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HighPass, Resampler, SampleBuffer, CPU_CLOCK_RATE, TAPS};
+    use crate::apu::{Channel, ChannelOutputs};
+
+    #[test]
+    fn test_mixer_levels() {
+        assert_eq!(ChannelOutputs::default().mix(), 0.0);
+
+        let pulses = ChannelOutputs {
+            pulse_1: 15,
+            pulse_2: 15,
+            ..Default::default()
+        };
+        assert!((pulses.mix() - 0.2585).abs() < 0.0001);
+
+        let everything = ChannelOutputs {
+            pulse_1: 15,
+            pulse_2: 15,
+            triangle: 15,
+            noise: 15,
+            dmc: 127,
+        };
+        assert!((everything.mix() - 1.0).abs() < 0.01);
+
+        // Two pulses aren't twice as loud as one
+        let one = ChannelOutputs {
+            pulse_1: 15,
+            ..Default::default()
+        };
+        assert!(one.mix() * 2.0 > pulses.mix());
+        assert_eq!(pulses.only(Channel::Pulse1), one);
+    }
+
+    #[test]
+    fn test_sample_buffer_drops_oldest() {
+        let mut buffer = SampleBuffer::new(3);
+        for sample in 0..5 {
+            buffer.push(sample as f32);
+        }
+        assert_eq!(buffer.len(), 3);
+
+        let mut out = [0.0; 2];
+        assert_eq!(buffer.read(&mut out), 2);
+        assert_eq!(out, [2.0, 3.0]);
+        assert_eq!(buffer.take_all(), vec![4.0]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_resampler_output_rate() {
+        for rate in [44_100, 48_000] {
+            let mut resampler = Resampler::new(rate);
+            let samples = (0..CPU_CLOCK_RATE as usize)
+                .filter_map(|_| resampler.clock(0.0))
+                .count();
+            assert!(samples.abs_diff(rate as usize) <= 1);
+        }
+    }
+
+    #[test]
+    fn test_resampler_step_settles() {
+        let mut resampler = Resampler::new(44_100);
+        let samples: Vec<f32> = (0..10_000).filter_map(|_| resampler.clock(0.5)).collect();
+        // The step is smeared over the kernel, then holds
+        assert!(samples[0] < 0.5);
+        for sample in &samples[TAPS..] {
+            assert!((sample - 0.5).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut filter = HighPass::new(44_100);
+        let first = filter.filter(1.0);
+        assert!(first > 0.98);
+        let last = (0..44_100).map(|_| filter.filter(1.0)).last().unwrap();
+        assert!(last.abs() < 0.001);
+    }
+}
@@ -0,0 +1,212 @@
+// Timer periods in CPU cycles
+const RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// The delta modulation channel, $4010-$4013. It plays 1-bit delta encoded
+/// samples that it reads out of PRG space itself, stalling the CPU for each
+/// byte it fetches.
+#[derive(Debug)]
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    /// 7-bit output level, nudged up or down by 2 for each sample bit
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub(super) irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            period: RATES[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0b0100_0000 != 0;
+                self.period = RATES[(data & 0b1111) as usize];
+            }
+            1 => self.level = data & 0b0111_1111,
+            // Samples start at $C000 + A * 64 and are L * 16 + 1 bytes long
+            2 => self.sample_address = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_length = ((data as u16) << 4) | 1,
+        }
+    }
+
+    /// Writing $4015 acknowledges the IRQ, and either stops the sample or
+    /// starts it over if it had finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn dma_address(&self) -> Option<u16> {
+        (self.sample_buffer.is_none() && self.bytes_remaining > 0).then_some(self.current_address)
+    }
+
+    pub fn load_sample(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // The address wraps around to $8000 rather than into I/O space
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    pub fn tick_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift_register = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Dmc;
+
+    #[test]
+    fn test_sample_fetches_and_irq() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0, 0b1000_0000);
+        dmc.write_register(2, 0xFF);
+        dmc.write_register(3, 0);
+        assert_eq!(dmc.dma_address(), None);
+
+        dmc.set_enabled(true);
+        assert_eq!(dmc.dma_address(), Some(0xFFC0));
+        dmc.load_sample(0xFF);
+        // Buffer's full again until the output unit takes it
+        assert_eq!(dmc.dma_address(), None);
+        assert!(!dmc.active());
+        assert!(dmc.irq);
+
+        dmc.set_enabled(false);
+        assert!(!dmc.irq);
+    }
+
+    #[test]
+    fn test_address_wraps_to_8000() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(2, 0xFF);
+        dmc.write_register(3, 0x10);
+        dmc.set_enabled(true);
+        for _ in 0..64 {
+            let address = dmc.dma_address().unwrap();
+            dmc.load_sample(0);
+            dmc.sample_buffer = None;
+            assert!(address >= 0xFFC0);
+        }
+        assert_eq!(dmc.dma_address(), Some(0x8000));
+    }
+
+    #[test]
+    fn test_looping_restarts_sample() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0, 0b1100_0000);
+        dmc.write_register(2, 0x01);
+        dmc.set_enabled(true);
+        dmc.load_sample(0);
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+        dmc.sample_buffer = None;
+        assert_eq!(dmc.dma_address(), Some(0xC040));
+    }
+
+    #[test]
+    fn test_output_level_follows_sample_bits() {
+        let mut dmc = Dmc::new();
+        dmc.write_register(0, 0x0F);
+        dmc.write_register(1, 64);
+        dmc.write_register(3, 0);
+        dmc.set_enabled(true);
+        dmc.load_sample(0b0000_0111);
+
+        // The first byte is picked up at the end of the current, silent,
+        // output cycle
+        for _ in 0..8 * 54 {
+            dmc.tick_timer();
+        }
+        assert_eq!(dmc.output(), 64);
+        for _ in 0..8 * 54 {
+            dmc.tick_timer();
+        }
+        assert_eq!(dmc.output(), 64 + 6 - 10);
+    }
+}
@@ -0,0 +1,559 @@
+use self::{audio::SampleStream, dmc::Dmc, noise::Noise, pulse::Pulse, triangle::Triangle};
+use crate::error::EmulatorError;
+
+pub use self::{
+    audio::{SampleBuffer, CPU_CLOCK_RATE},
+    wav::{write_wav, Recording},
+};
+
+mod audio;
+mod dmc;
+mod noise;
+mod pulse;
+mod triangle;
+mod wav;
+
+const PULSE_1: u16 = 0x4000;
+const PULSE_1_END: u16 = 0x4003;
+const PULSE_2: u16 = 0x4004;
+const PULSE_2_END: u16 = 0x4007;
+const TRIANGLE: u16 = 0x4008;
+const TRIANGLE_END: u16 = 0x400B;
+const NOISE: u16 = 0x400C;
+const NOISE_END: u16 = 0x400F;
+const DMC: u16 = 0x4010;
+const DMC_END: u16 = 0x4013;
+const STATUS: u16 = 0x4015;
+const FRAME_COUNTER: u16 = 0x4017;
+
+// CPU cycles into the sequence at which the frame counter steps. The four
+// step sequence ends on the last one, the five step sequence idles through
+// it and steps once more later.
+const FRAME_STEPS: [u32; 4] = [7457, 14913, 22371, 29829];
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_LAST: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+/// The resampler makes at most one sample per CPU cycle
+pub const MAX_SAMPLE_RATE: u32 = CPU_CLOCK_RATE as u32;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// What each channel is putting out right now, before mixing. The pulses
+/// and noise range over 0-15, the triangle 0-15 and the DMC 0-127.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelOutputs {
+    pub pulse_1: u8,
+    pub pulse_2: u8,
+    pub triangle: u8,
+    pub noise: u8,
+    pub dmc: u8,
+}
+
+/// One of the APU's five sound channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [
+        Channel::Pulse1,
+        Channel::Pulse2,
+        Channel::Triangle,
+        Channel::Noise,
+        Channel::Dmc,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse_1",
+            Channel::Pulse2 => "pulse_2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+/// The 2A03's audio unit, registers $4000-$4017
+#[derive(Debug)]
+pub struct APU {
+    pulse_1: Pulse,
+    pulse_2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    // The pulse timers only run on every other CPU cycle
+    odd_cycle: bool,
+    sample_rate: u32,
+    output: SampleStream,
+    /// Each channel on its own, in `Channel::ALL` order, when stems are on
+    stems: Vec<SampleStream>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl APU {
+    pub fn new() -> Self {
+        APU {
+            // Pulse 1 negates its sweep with ones' complement
+            pulse_1: Pulse::new(true),
+            pulse_2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            output: SampleStream::new(DEFAULT_SAMPLE_RATE),
+            stems: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Switches the output to `rate` samples per second, from 1 up to
+    /// `MAX_SAMPLE_RATE`. Samples that were still waiting at the old rate
+    /// are thrown away.
+    pub fn set_sample_rate(&mut self, rate: u32) -> Result<(), EmulatorError> {
+        if !(1..=MAX_SAMPLE_RATE).contains(&rate) {
+            return Err(EmulatorError::InvalidSampleRate(rate));
+        }
+        self.sample_rate = rate;
+        self.output = SampleStream::new(rate);
+        self.set_stems(self.stems_enabled());
+        Ok(())
+    }
+
+    /// Mixed mono samples at the output rate, ready for the host
+    pub fn samples(&mut self) -> &mut SampleBuffer {
+        &mut self.output.samples
+    }
+
+    /// Also renders every channel into its own buffer, as if the others were
+    /// muted. This costs a resampler per channel so it is off by default.
+    pub fn set_stems(&mut self, enabled: bool) {
+        self.stems = if enabled {
+            Channel::ALL
+                .iter()
+                .map(|_| SampleStream::new(self.sample_rate))
+                .collect()
+        } else {
+            Vec::new()
+        };
+    }
+
+    pub fn stems_enabled(&self) -> bool {
+        !self.stems.is_empty()
+    }
+
+    /// The samples for just `channel`, if stems are on
+    pub fn stem_samples(&mut self, channel: Channel) -> Option<&mut SampleBuffer> {
+        let index = Channel::ALL.iter().position(|c| *c == channel)?;
+        self.stems.get_mut(index).map(|stem| &mut stem.samples)
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            PULSE_1..=PULSE_1_END => self.pulse_1.write_register(addr - PULSE_1, data),
+            PULSE_2..=PULSE_2_END => self.pulse_2.write_register(addr - PULSE_2, data),
+            TRIANGLE..=TRIANGLE_END => self.triangle.write_register(addr - TRIANGLE, data),
+            NOISE..=NOISE_END => self.noise.write_register(addr - NOISE, data),
+            DMC..=DMC_END => self.dmc.write_register(addr - DMC, data),
+            STATUS => {
+                self.pulse_1.length.set_enabled(data & 0b0_0001 != 0);
+                self.pulse_2.length.set_enabled(data & 0b0_0010 != 0);
+                self.triangle.length.set_enabled(data & 0b0_0100 != 0);
+                self.noise.length.set_enabled(data & 0b0_1000 != 0);
+                self.dmc.set_enabled(data & 0b1_0000 != 0);
+            }
+            FRAME_COUNTER => {
+                self.five_step = data & 0b1000_0000 != 0;
+                self.irq_inhibit = data & 0b0100_0000 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // Picking the five step sequence clocks everything straight away
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Reads $4015, which acknowledges the frame IRQ
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    /// Reads $4015 without acknowledging the frame IRQ
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        status |= self.pulse_1.length.active() as u8;
+        status |= (self.pulse_2.length.active() as u8) << 1;
+        status |= (self.triangle.length.active() as u8) << 2;
+        status |= (self.noise.length.active() as u8) << 3;
+        status |= (self.dmc.active() as u8) << 4;
+        status |= (self.frame_irq as u8) << 6;
+        status |= (self.dmc.irq as u8) << 7;
+        status
+    }
+
+    /// Whether the frame counter or the DMC is pulling the IRQ line low
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// The address the DMC wants its next sample byte read from, when its
+    /// buffer has run dry. The bus reads it and hands it to `load_dmc_sample`.
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.dma_address()
+    }
+
+    pub fn load_dmc_sample(&mut self, data: u8) {
+        self.dmc.load_sample(data);
+    }
+
+    pub fn outputs(&self) -> ChannelOutputs {
+        ChannelOutputs {
+            pulse_1: self.pulse_1.output(),
+            pulse_2: self.pulse_2.output(),
+            triangle: self.triangle.output(),
+            noise: self.noise.output(),
+            dmc: self.dmc.output(),
+        }
+    }
+
+    /// Runs the APU for one CPU cycle
+    pub fn tick(&mut self) {
+        self.triangle.tick_timer();
+        self.noise.tick_timer();
+        self.dmc.tick_timer();
+        if self.odd_cycle {
+            self.pulse_1.tick_timer();
+            self.pulse_2.tick_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.tick_frame_counter();
+
+        let outputs = self.outputs();
+        self.output.clock(outputs.mix());
+        for (stem, channel) in self.stems.iter_mut().zip(Channel::ALL) {
+            stem.clock(outputs.only(channel).mix());
+        }
+    }
+
+    fn tick_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let cycle = self.frame_cycle;
+        if cycle == FRAME_STEPS[0] || cycle == FRAME_STEPS[2] {
+            self.quarter_frame();
+        } else if cycle == FRAME_STEPS[1]
+            || (cycle == FRAME_STEPS[3] && !self.five_step)
+            || (cycle == FIVE_STEP_LAST && self.five_step)
+        {
+            self.quarter_frame();
+            self.half_frame();
+        }
+
+        if cycle == FRAME_STEPS[3] && !self.five_step && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+        let period = if self.five_step {
+            FIVE_STEP_PERIOD
+        } else {
+            FOUR_STEP_PERIOD
+        };
+        if cycle >= period {
+            self.frame_cycle = 0;
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn quarter_frame(&mut self) {
+        self.pulse_1.envelope.clock();
+        self.pulse_2.envelope.clock();
+        self.triangle.clock_linear_counter();
+        self.noise.envelope.clock();
+    }
+
+    // Length counters and sweeps
+    fn half_frame(&mut self) {
+        self.pulse_1.half_frame();
+        self.pulse_2.half_frame();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+}
+
+/// Silences a channel once the note it was given has run its length
+#[derive(Debug, Default)]
+struct LengthCounter {
+    enabled: bool,
+    halted: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    // Disabling a channel through $4015 also cuts its note short
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    // Loads from the top five bits of a channel's last register
+    fn load(&mut self, data: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[(data >> 3) as usize];
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halted && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+/// A decaying volume, or a constant one, for the pulse and noise channels
+#[derive(Debug, Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    // The constant volume, and the divider's period otherwise
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // The --LC VVVV bits of a channel's first register
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    fn restart(&mut self) {
+        self.start = true;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Channel, APU, CPU_CLOCK_RATE, FOUR_STEP_PERIOD, FRAME_STEPS, MAX_SAMPLE_RATE};
+    use crate::error::EmulatorError;
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn test_length_counters_in_status() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0000_1111);
+        // Length index 1 is 254, index 0 is 10
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.write_register(0x400F, 0b0000_0000);
+        assert_eq!(apu.peek_status(), 0b0000_1001);
+
+        // Ten half frames run the noise out, two per four step sequence
+        run(&mut apu, FOUR_STEP_PERIOD * 5);
+        assert_eq!(apu.peek_status() & 0b1111, 0b0000_0001);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.peek_status() & 0b1111, 0);
+    }
+
+    #[test]
+    fn test_disabled_channels_ignore_length_loads() {
+        let mut apu = APU::new();
+        apu.write_register(0x4003, 0b0000_1000);
+        assert_eq!(apu.peek_status(), 0);
+    }
+
+    #[test]
+    fn test_four_step_frame_irq() {
+        let mut apu = APU::new();
+        run(&mut apu, FRAME_STEPS[3] - 1);
+        assert!(!apu.irq());
+        run(&mut apu, 1);
+        assert!(apu.irq());
+
+        // Reading $4015 acknowledges it
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq());
+        assert_eq!(apu.peek_status() & 0b0100_0000, 0);
+    }
+
+    #[test]
+    fn test_irq_inhibit_and_five_step_mode() {
+        let mut apu = APU::new();
+        run(&mut apu, FRAME_STEPS[3]);
+        apu.write_register(0x4017, 0b0100_0000);
+        assert!(!apu.irq());
+        run(&mut apu, FOUR_STEP_PERIOD * 2);
+        assert!(!apu.irq());
+
+        apu.write_register(0x4017, 0b1000_0000);
+        run(&mut apu, FOUR_STEP_PERIOD * 2);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn test_five_step_write_clocks_half_frame() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        // Length index 3 is 2
+        apu.write_register(0x4003, 0b0001_1000);
+        apu.write_register(0x4017, 0b1000_0000);
+        apu.write_register(0x4017, 0b1000_0000);
+        assert_eq!(apu.peek_status() & 1, 0);
+    }
+
+    #[test]
+    fn test_rejects_sample_rates_out_of_range() {
+        let mut apu = APU::new();
+        for rate in [0, MAX_SAMPLE_RATE + 1, 2_000_000] {
+            assert!(matches!(
+                apu.set_sample_rate(rate),
+                Err(EmulatorError::InvalidSampleRate(r)) if r == rate
+            ));
+        }
+        assert_eq!(apu.sample_rate(), 44_100);
+
+        // The top rate makes a sample every CPU cycle
+        apu.set_sample_rate(MAX_SAMPLE_RATE).unwrap();
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b0000_1000);
+        run(&mut apu, 1000);
+        assert!(apu.samples().len().abs_diff(1000) <= 1);
+    }
+
+    #[test]
+    fn test_samples_at_selected_rate() {
+        let mut apu = APU::new();
+        assert_eq!(apu.sample_rate(), 44_100);
+        apu.set_sample_rate(48_000).unwrap();
+        apu.write_register(0x4015, 0b0000_0001);
+        // A 50% duty square at a constant volume of 15, about 440Hz
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0b0000_1000);
+        run(&mut apu, CPU_CLOCK_RATE as u32 / 10);
+
+        let samples = apu.samples().take_all();
+        assert!(samples.len().abs_diff(4800) <= 1);
+        // The triangle idles at 15, which is a DC step at power on that the
+        // high-pass takes a few milliseconds to settle
+        let peak = samples[2400..]
+            .iter()
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.05 && peak < 0.2);
+    }
+
+    #[test]
+    fn test_stems_only_hear_their_channel() {
+        let mut apu = APU::new();
+        apu.set_stems(true);
+        apu.write_register(0x4015, 0b0000_0010);
+        apu.write_register(0x4004, 0b1011_1111);
+        apu.write_register(0x4006, 0xFD);
+        apu.write_register(0x4007, 0b0000_1000);
+        run(&mut apu, CPU_CLOCK_RATE as u32 / 10);
+
+        let loudest = |samples: Vec<f32>| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let pulse_2 = loudest(apu.stem_samples(Channel::Pulse2).unwrap().take_all());
+        let pulse_1 = loudest(apu.stem_samples(Channel::Pulse1).unwrap().take_all());
+        assert!(pulse_2 > 0.05);
+        assert_eq!(pulse_1, 0.0);
+
+        apu.set_stems(false);
+        assert!(apu.stem_samples(Channel::Pulse2).is_none());
+    }
+
+    #[test]
+    fn test_envelope_decays_and_loops() {
+        let mut apu = APU::new();
+        apu.write_register(0x4015, 0b0000_0001);
+        // Duty 50%, looping envelope with a divider period of 0
+        apu.write_register(0x4000, 0b1010_0000);
+        apu.write_register(0x4002, 0x40);
+        apu.write_register(0x4003, 0b0000_1000);
+        apu.pulse_1.envelope.clock();
+        assert_eq!(apu.pulse_1.envelope.output(), 15);
+        for _ in 0..15 {
+            apu.pulse_1.envelope.clock();
+        }
+        assert_eq!(apu.pulse_1.envelope.output(), 0);
+        apu.pulse_1.envelope.clock();
+        assert_eq!(apu.pulse_1.envelope.output(), 15);
+
+        // Constant volume just outputs the volume bits
+        apu.write_register(0x4000, 0b1011_0111);
+        assert_eq!(apu.pulse_1.envelope.output(), 7);
+    }
+}
@@ -0,0 +1,111 @@
+use super::{Envelope, LengthCounter};
+
+// Timer periods in CPU cycles
+const PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// The noise channel, $400C-$400F, driven by a 15-bit linear feedback shift
+/// register
+#[derive(Debug)]
+pub struct Noise {
+    /// Feeds back from bit 6 instead of bit 1, giving a short, metallic loop
+    short_mode: bool,
+    shift_register: u16,
+    period: u16,
+    timer: u16,
+    pub(super) length: LengthCounter,
+    pub(super) envelope: Envelope,
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Noise {
+            short_mode: false,
+            // The register powers up as 1, it would get stuck at 0
+            shift_register: 1,
+            period: PERIODS[0],
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // --LC VVVV
+            0 => {
+                self.length.halted = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            // M--- PPPP
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = PERIODS[(data & 0b1111) as usize];
+            }
+            // LLLL L---
+            _ => {
+                self.length.load(data);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    pub fn tick_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift_register & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Noise;
+
+    // How many clocks the shift register takes to come back around
+    fn sequence_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new();
+        noise.short_mode = short_mode;
+        noise.period = 1;
+        let start = noise.shift_register;
+        (1..)
+            .find(|_| {
+                noise.tick_timer();
+                noise.shift_register == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lfsr_sequence_lengths() {
+        assert_eq!(sequence_length(false), 32767);
+        assert_eq!(sequence_length(true), 93);
+    }
+
+    #[test]
+    fn test_output_follows_bit_zero() {
+        let mut noise = Noise::new();
+        noise.length.set_enabled(true);
+        noise.write_register(0, 0b0001_0101);
+        noise.write_register(3, 0b0000_1000);
+        // Bit 0 starts set, which silences the channel
+        assert_eq!(noise.output(), 0);
+        noise.shift_register = 0b10;
+        assert_eq!(noise.output(), 5);
+    }
+}
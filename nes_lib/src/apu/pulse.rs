@@ -0,0 +1,193 @@
+use super::{Envelope, LengthCounter};
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// Periods this far out of range silence the channel
+const MIN_PERIOD: u16 = 8;
+const MAX_PERIOD: u16 = 0x7FF;
+
+/// One of the two square wave channels, $4000-$4003 and $4004-$4007
+#[derive(Debug)]
+pub struct Pulse {
+    /// Pulse 1 subtracts one more when its sweep negates
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    pub(super) length: LengthCounter,
+    pub(super) envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: LengthCounter::default(),
+            envelope: Envelope::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_reload: false,
+            sweep_divider: 0,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // DDLC VVVV
+            0 => {
+                self.duty = data >> 6;
+                self.length.halted = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            // LLLL LHHH, which also restarts the note
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0b111) << 8);
+                self.length.load(data);
+                self.envelope.restart();
+                self.step = 0;
+            }
+        }
+    }
+
+    /// Clocked every other CPU cycle
+    pub fn tick_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn half_frame(&mut self) {
+        self.length.clock();
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // Where the sweep would move the period to. This is worked out all the
+    // time, since it mutes the channel even when the sweep is off.
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            let change = change + self.ones_complement as u16;
+            self.period.saturating_sub(change)
+        } else {
+            self.period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < MIN_PERIOD || self.target_period() > MAX_PERIOD
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.length.active()
+            || self.muted()
+            || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pulse;
+
+    fn playing(ones_complement: bool, period: u16) -> Pulse {
+        let mut pulse = Pulse::new(ones_complement);
+        pulse.length.set_enabled(true);
+        // 75% duty at a constant volume of 9
+        pulse.write_register(0, 0b1101_1001);
+        pulse.write_register(2, period as u8);
+        pulse.write_register(3, 0b0000_1000 | (period >> 8) as u8);
+        pulse
+    }
+
+    #[test]
+    fn test_duty_cycle_output() {
+        let mut pulse = playing(false, 8);
+        let mut wave = vec![];
+        for _ in 0..8 {
+            wave.push(pulse.output());
+            for _ in 0..=8 {
+                pulse.tick_timer();
+            }
+        }
+        assert_eq!(wave, vec![9, 0, 0, 9, 9, 9, 9, 9]);
+    }
+
+    #[test]
+    fn test_short_and_overflowing_periods_mute() {
+        let pulse = playing(false, 7);
+        assert_eq!(pulse.output(), 0);
+        assert!(pulse.muted());
+
+        // Even a disabled sweep mutes when its target would overflow
+        let pulse = playing(false, 0x700);
+        assert!(pulse.muted());
+    }
+
+    #[test]
+    fn test_sweep_negation_differs_between_channels() {
+        for (ones_complement, target) in [(true, 0x0FF), (false, 0x100)] {
+            let mut pulse = playing(ones_complement, 0x200);
+            // Enabled, period 0, negate, shift 1
+            pulse.write_register(1, 0b1000_1001);
+            pulse.half_frame();
+            assert_eq!(pulse.period, target);
+        }
+    }
+
+    #[test]
+    fn test_sweep_divider_period() {
+        let mut pulse = playing(false, 0x100);
+        // Enabled, period 1, shift 2
+        pulse.write_register(1, 0b1001_0010);
+        // The divider starts out at zero, then waits a half frame between
+        // adjustments
+        pulse.half_frame();
+        assert_eq!(pulse.period, 0x140);
+        pulse.half_frame();
+        assert_eq!(pulse.period, 0x140);
+        pulse.half_frame();
+        assert_eq!(pulse.period, 0x190);
+    }
+}
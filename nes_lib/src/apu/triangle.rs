@@ -0,0 +1,129 @@
+use super::LengthCounter;
+
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The triangle wave channel, $4008-$400B. It has no volume control, only a
+/// second, finer grained counter to cut notes off with.
+#[derive(Debug)]
+pub struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    pub(super) length: LengthCounter,
+    /// Doubles as the length counter halt flag
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Self {
+        Triangle {
+            step: 0,
+            period: 0,
+            timer: 0,
+            length: LengthCounter::default(),
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8) {
+        match register {
+            // CRRR RRRR
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halted = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | data as u16,
+            // LLLL LHHH
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0b111) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle. The sequencer holds its position while
+    /// either counter is zero, so the channel goes quiet without popping.
+    pub fn tick_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % SEQUENCE.len() as u8;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Triangle;
+
+    fn tick(triangle: &mut Triangle, steps: usize) {
+        for _ in 0..steps * 3 {
+            triangle.tick_timer();
+        }
+    }
+
+    #[test]
+    fn test_linear_counter_gates_sequencer() {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        triangle.write_register(0, 2);
+        triangle.write_register(2, 2);
+        triangle.write_register(3, 0b0000_1000);
+
+        // Nothing plays until a quarter frame loads the linear counter
+        tick(&mut triangle, 4);
+        assert_eq!(triangle.output(), 15);
+
+        triangle.clock_linear_counter();
+        tick(&mut triangle, 4);
+        assert_eq!(triangle.output(), 11);
+
+        // Two more quarter frames run it out and the output holds
+        triangle.clock_linear_counter();
+        triangle.clock_linear_counter();
+        tick(&mut triangle, 4);
+        assert_eq!(triangle.output(), 11);
+    }
+
+    #[test]
+    fn test_control_flag_keeps_reloading() {
+        let mut triangle = Triangle::new();
+        triangle.length.set_enabled(true);
+        triangle.write_register(0, 0b1000_0001);
+        triangle.write_register(3, 0b0000_1000);
+        for _ in 0..10 {
+            triangle.clock_linear_counter();
+        }
+        assert_eq!(triangle.linear_counter, 1);
+    }
+}
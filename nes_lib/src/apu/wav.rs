@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::{
+    bus::NesBus,
+    cpu::{StopReason, CPU},
+    error::EmulatorError,
+};
+
+use super::{Channel, APU};
+
+const BITS_PER_SAMPLE: u16 = 16;
+const PCM_FORMAT: u16 = 1;
+
+/// Audio captured from a run with no frontend attached
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub sample_rate: u32,
+    pub mix: Vec<f32>,
+    /// Each channel on its own, when the recording asked for stems
+    pub stems: Vec<(Channel, Vec<f32>)>,
+}
+
+impl Recording {
+    /// Runs the console for `frames` video frames and keeps everything the
+    /// APU puts out. BRK doesn't stop the run like it does by default, since
+    /// games use it as an ordinary instruction. The CPU's own `halt_on_brk`
+    /// and the APU's stem setting are put back before returning.
+    pub fn capture(cpu: &mut CPU<NesBus>, frames: u64, stems: bool) -> Result<Self, EmulatorError> {
+        let apu = cpu.bus.apu_mut();
+        let stems_enabled = apu.stems_enabled();
+        apu.set_stems(stems);
+        apu.samples().clear();
+        let mut recording = Recording {
+            sample_rate: apu.sample_rate(),
+            mix: Vec::new(),
+            stems: Vec::new(),
+        };
+        if stems {
+            recording.stems = Channel::ALL
+                .iter()
+                .map(|channel| (*channel, Vec::new()))
+                .collect();
+        }
+
+        let halt_on_brk = std::mem::replace(&mut cpu.halt_on_brk, false);
+        let result = recording.run(cpu, frames);
+        cpu.halt_on_brk = halt_on_brk;
+        if stems != stems_enabled {
+            cpu.bus.apu_mut().set_stems(stems_enabled);
+        }
+        result.map(|_| recording)
+    }
+
+    fn run(&mut self, cpu: &mut CPU<NesBus>, frames: u64) -> Result<(), EmulatorError> {
+        for _ in 0..frames {
+            let reason = cpu.run_frames(1)?;
+            // Samples are collected every frame so the buffers never fill up
+            self.collect(cpu.bus.apu_mut());
+            if reason != StopReason::FrameBudget {
+                warn!("Recording stopped early: {:?}", reason);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn collect(&mut self, apu: &mut APU) {
+        self.mix.extend(apu.samples().take_all());
+        for (channel, samples) in &mut self.stems {
+            if let Some(stem) = apu.stem_samples(*channel) {
+                samples.extend(stem.take_all());
+            }
+        }
+    }
+
+    /// Writes the mix to `path`. Stems go next to it, named after the mix
+    /// without its extension, so `song.wav` gets `song.triangle.wav`.
+    pub fn write(&self, path: &Path) -> Result<(), EmulatorError> {
+        write_wav(path, self.sample_rate, &self.mix)?;
+        for (channel, samples) in &self.stems {
+            write_wav(&stem_path(path, *channel), self.sample_rate, samples)?;
+        }
+        Ok(())
+    }
+}
+
+fn stem_path(path: &Path, channel: Channel) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.wav", channel.name()));
+    path.with_file_name(name)
+}
+
+/// Saves mono samples between -1 and 1 as a 16-bit PCM .wav file
+pub fn write_wav(path: &Path, sample_rate: u32, samples: &[f32]) -> Result<(), EmulatorError> {
+    std::fs::write(path, encode_wav(sample_rate, samples))?;
+    Ok(())
+}
+
+fn encode_wav(sample_rate: u32, samples: &[f32]) -> Vec<u8> {
+    let block_align = BITS_PER_SAMPLE / 8;
+    let data_len = samples.len() as u32 * block_align as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&PCM_FORMAT.to_le_bytes());
+    // Mono
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let pcm = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        wav.extend_from_slice(&pcm.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{encode_wav, stem_path, Recording};
+    use crate::{apu::Channel, cartridge::Cartridge, cpu::CPU};
+
+    #[test]
+    fn test_wav_header_and_samples() {
+        let wav = encode_wav(44_100, &[0.0, 1.0, -1.0, 2.0]);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 44_100);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 8);
+
+        let samples: Vec<i16> = wav[44..]
+            .chunks_exact(2)
+            .map(|pcm| i16::from_le_bytes([pcm[0], pcm[1]]))
+            .collect();
+        assert_eq!(samples, vec![0, i16::MAX, -i16::MAX, i16::MAX]);
+    }
+
+    #[test]
+    fn test_stem_paths_always_end_in_wav() {
+        for output in ["out/song.wav", "out/song"] {
+            assert_eq!(
+                stem_path(Path::new(output), Channel::Triangle),
+                PathBuf::from("out/song.triangle.wav")
+            );
+        }
+    }
+
+    #[test]
+    fn test_capture_runs_rom_headless() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // Start a tone on the triangle channel, then spin
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0x04, 0x8D, 0x15, 0x40, // LDA #$04; STA $4015
+            0xA9, 0xFF, 0x8D, 0x08, 0x40, // LDA #$FF; STA $4008
+            0xA9, 0x7F, 0x8D, 0x0A, 0x40, // LDA #$7F; STA $400A
+            0xA9, 0x08, 0x8D, 0x0B, 0x40, // LDA #$08; STA $400B
+            0x00, 0x00,                   // BRK, which returns to the loop
+            0x4C, 0x16, 0x80,             // loop: JMP loop
+        ];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        // RTI at $8100 handles the BRK
+        prg_rom[0x100] = 0x40;
+        prg_rom[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x81]);
+        let mut cpu = CPU::from_cartridge(Cartridge::new(0, prg_rom, vec![])).unwrap();
+
+        let recording = Recording::capture(&mut cpu, 30, true).unwrap();
+        assert!(cpu.halt_on_brk);
+        assert!(!cpu.bus.apu().stems_enabled());
+        // Half a second at 44.1kHz, give or take the partial first frame
+        assert!(recording.mix.len().abs_diff(22_050) < 1000);
+        assert_eq!(recording.stems.len(), 5);
+
+        let loudest = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(loudest(&recording.mix[4410..]) > 0.05);
+        for (channel, samples) in &recording.stems {
+            assert_eq!(samples.len(), recording.mix.len());
+            if *channel != Channel::Triangle {
+                assert_eq!(loudest(samples), 0.0);
+            }
+        }
+
+        // Stems the host turned on stay on after a recording without them
+        cpu.bus.apu_mut().set_stems(true);
+        let recording = Recording::capture(&mut cpu, 1, false).unwrap();
+        assert!(recording.stems.is_empty());
+        assert!(cpu.bus.apu().stems_enabled());
+    }
+}
@@ -0,0 +1,571 @@
+use std::path::{Path, PathBuf};
+
+use tracing::warn;
+
+use crate::{
+    apu::APU,
+    cartridge::Cartridge,
+    controller::{ButtonState, InputDevice, Joypad, Port},
+    error::EmulatorError,
+    mapper::{self, Mapper},
+    ppu::PPU,
+};
+
+/// The CPU's view of the 16-bit address space. Anything the CPU talks to
+/// (RAM, PPU and APU registers, controllers, cartridges) sits behind this.
+pub trait Bus {
+    fn read(&mut self, addr: u16) -> u8;
+
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Reads without side effects, for debuggers and the frontend
+    fn peek(&self, addr: u16) -> u8;
+
+    /// Called after every instruction with the CPU cycles it took, so the
+    /// components on the bus can catch up
+    fn tick(&mut self, _cycles: u16) {}
+
+    /// Runs any DMA transfer the last instruction started and returns how
+    /// many cycles DMA has halted the CPU for since the last poll. Some
+    /// transfers take an extra cycle to line up when the CPU is on an odd
+    /// cycle.
+    fn poll_dma(&mut self, _odd_cycle: bool) -> u16 {
+        0
+    }
+
+    /// Takes a pending NMI edge, such as the PPU entering VBlank
+    fn poll_nmi(&mut self) -> bool {
+        false
+    }
+
+    /// Whether any device on the bus is pulling the IRQ line low
+    fn irq(&self) -> bool {
+        false
+    }
+}
+
+/// 64KiB of plain RAM with no mirroring, used by the unit tests and to run
+/// the assembler's output
+#[derive(Debug)]
+pub struct FlatRam {
+    memory: Vec<u8>,
+}
+
+impl Default for FlatRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatRam {
+    pub fn new() -> Self {
+        FlatRam {
+            memory: vec![0; 0x10000],
+        }
+    }
+
+    pub fn load(&mut self, start: u16, program: &[u8]) {
+        let start = start as usize;
+        self.memory[start..(start + program.len())].copy_from_slice(program);
+    }
+}
+
+impl Bus for FlatRam {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.memory[addr as usize] = data;
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.memory[addr as usize]
+    }
+}
+
+const RAM: u16 = 0x0000;
+const RAM_MIRRORS_END: u16 = 0x1FFF;
+const PPU_REGISTERS: u16 = 0x2000;
+const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_IO_REGISTERS: u16 = 0x4000;
+const APU_IO_REGISTERS_END: u16 = 0x4017;
+const TEST_MODE_REGISTERS: u16 = 0x4018;
+const TEST_MODE_REGISTERS_END: u16 = 0x401F;
+const CARTRIDGE: u16 = 0x4020;
+const CARTRIDGE_END: u16 = 0xFFFF;
+
+const OAM_DATA: u16 = 0x2004;
+const OAM_DMA: u16 = 0x4014;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD_1: u16 = 0x4016;
+const JOYPAD_2: u16 = 0x4017;
+// Controllers only drive the low data lines, the rest are left floating
+const JOYPAD_OPEN_BUS: u8 = 0b1110_0000;
+// Cycles OAM DMA takes when it starts on an even CPU cycle
+const OAM_DMA_CYCLES: u16 = 513;
+// Cycles the CPU is halted for each byte the DMC fetches
+const DMC_DMA_CYCLES: u16 = 4;
+
+/// Where an address on the NES CPU bus ends up, with mirrors folded away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// Offset into the 2KiB of internal RAM
+    Ram(u16),
+    /// One of the eight PPU registers, $2000-$2007
+    PpuRegister(u16),
+    /// APU, OAM DMA and controller registers, $4000-$4017
+    ApuIo(u16),
+    /// APU and I/O test functionality that is disabled on retail units
+    TestMode(u16),
+    /// Everything from $4020 up belongs to the cartridge
+    Cartridge(u16),
+}
+
+impl Region {
+    pub fn decode(addr: u16) -> Region {
+        match addr {
+            RAM..=RAM_MIRRORS_END => Region::Ram(addr & 0x07FF),
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => Region::PpuRegister(addr & 0x2007),
+            APU_IO_REGISTERS..=APU_IO_REGISTERS_END => Region::ApuIo(addr),
+            TEST_MODE_REGISTERS..=TEST_MODE_REGISTERS_END => Region::TestMode(addr),
+            CARTRIDGE..=CARTRIDGE_END => Region::Cartridge(addr),
+        }
+    }
+}
+
+/// The NES CPU memory map
+#[derive(Debug)]
+pub struct NesBus {
+    cpu_vram: [u8; 2048],
+    ppu: PPU,
+    apu: APU,
+    /// What is plugged into each controller port, standard pads by default
+    controllers: [Box<dyn InputDevice>; 2],
+    mapper: Box<dyn Mapper>,
+    /// The last value driven onto the data bus. Reads from addresses nothing
+    /// answers for see this value, usually the high byte of the address.
+    open_bus: u8,
+    /// Where battery backed RAM is kept between sessions
+    save_path: Option<PathBuf>,
+    /// The page a write to $4014 asked to copy into OAM
+    oam_dma_page: Option<u8>,
+    /// Cycles the DMC's sample fetches have stolen since the last poll
+    dmc_dma_cycles: u16,
+}
+
+impl NesBus {
+    pub fn new(mapper: Box<dyn Mapper>) -> Self {
+        NesBus {
+            cpu_vram: [0; 2048],
+            ppu: PPU::new(),
+            apu: APU::new(),
+            controllers: [Box::new(Joypad::new()), Box::new(Joypad::new())],
+            mapper,
+            open_bus: 0,
+            save_path: None,
+            oam_dma_page: None,
+            dmc_dma_cycles: 0,
+        }
+    }
+
+    pub fn from_cartridge(cartridge: Cartridge) -> Result<Self, EmulatorError> {
+        Ok(NesBus::new(mapper::from_cartridge(cartridge)?))
+    }
+
+    /// Opens a ROM file, keeping battery saves in a .sav file next to it
+    pub fn from_rom_file(path: &Path) -> Result<Self, EmulatorError> {
+        NesBus::from_cartridge(Cartridge::from_file(path)?)?
+            .with_save_file(path.with_extension("sav"))
+    }
+
+    /// Keeps battery backed RAM in the file at `path`, loading it now if it
+    /// already exists. Cartridges without a battery ignore this.
+    pub fn with_save_file(mut self, path: impl Into<PathBuf>) -> Result<Self, EmulatorError> {
+        let path = path.into();
+        if let Some(save_ram) = self.mapper.save_ram_mut() {
+            if path.exists() {
+                let data = std::fs::read(&path)?;
+                let len = data.len().min(save_ram.len());
+                save_ram[..len].copy_from_slice(&data[..len]);
+            }
+            self.save_path = Some(path);
+        }
+        Ok(self)
+    }
+
+    pub fn save_path(&self) -> Option<&Path> {
+        self.save_path.as_deref()
+    }
+
+    /// Writes battery backed RAM out to the save file
+    pub fn flush_save(&self) -> Result<(), EmulatorError> {
+        if let (Some(path), Some(save_ram)) = (&self.save_path, self.mapper.save_ram()) {
+            std::fs::write(path, save_ram)?;
+        }
+        Ok(())
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    /// Sets which buttons are held on the controller in `port`
+    pub fn set_buttons(&mut self, port: Port, buttons: ButtonState) {
+        self.controllers[port.index()].set_buttons(0, buttons);
+    }
+
+    /// Sets the buttons for a player counting from 0, with players 3 and 4
+    /// on the second controller of each port like the Four Score has them
+    pub fn set_player_buttons(&mut self, player: usize, buttons: ButtonState) {
+        self.controllers[player % 2].set_buttons(player / 2, buttons);
+    }
+
+    /// Plugs `device` into `port` in place of whatever was there
+    pub fn connect(&mut self, port: Port, device: Box<dyn InputDevice>) {
+        self.controllers[port.index()] = device;
+    }
+
+    pub fn input_device(&self, port: Port) -> &dyn InputDevice {
+        self.controllers[port.index()].as_ref()
+    }
+
+    pub fn input_device_mut(&mut self, port: Port) -> &mut dyn InputDevice {
+        self.controllers[port.index()].as_mut()
+    }
+
+    pub fn mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+
+    pub fn mapper_mut(&mut self) -> &mut dyn Mapper {
+        self.mapper.as_mut()
+    }
+
+    // The APU's registers are write only apart from $4015, and even that
+    // leaves bit 5 floating
+    fn read_apu_io(&self, addr: u16) -> Option<u8> {
+        match addr {
+            APU_STATUS => Some(self.apu.peek_status() | (self.open_bus & 0b0010_0000)),
+            JOYPAD_1 | JOYPAD_2 => {
+                let device = &self.controllers[(addr - JOYPAD_1) as usize];
+                Some(device.peek(&self.ppu) | (self.open_bus & JOYPAD_OPEN_BUS))
+            }
+            _ => None,
+        }
+    }
+
+    fn write_apu_io(&mut self, addr: u16, data: u8) {
+        match addr {
+            OAM_DMA => self.oam_dma_page = Some(data),
+            // Both ports share the strobe line
+            JOYPAD_1 => {
+                for device in &mut self.controllers {
+                    device.write_strobe(data & 1 != 0);
+                }
+            }
+            _ => self.apu.write_register(addr, data),
+        }
+    }
+}
+
+// Flush on shutdown too, so progress survives the console going away
+impl Drop for NesBus {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_save() {
+            warn!("Failed to write save file: {}", e);
+        }
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, addr: u16) -> u8 {
+        let data = match Region::decode(addr) {
+            Region::PpuRegister(register) => self.ppu.read_register(register, self.mapper.as_mut()),
+            Region::ApuIo(APU_STATUS) => self.apu.read_status() | (self.open_bus & 0b0010_0000),
+            Region::ApuIo(addr @ (JOYPAD_1 | JOYPAD_2)) => {
+                let device = &mut self.controllers[(addr - JOYPAD_1) as usize];
+                device.read(&self.ppu) | (self.open_bus & JOYPAD_OPEN_BUS)
+            }
+            _ => self.peek(addr),
+        };
+        self.open_bus = data;
+        data
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        self.open_bus = data;
+        match Region::decode(addr) {
+            Region::Ram(offset) => self.cpu_vram[offset as usize] = data,
+            Region::PpuRegister(register) => {
+                self.ppu
+                    .write_register(register, data, self.mapper.as_mut())
+            }
+            Region::ApuIo(addr) => self.write_apu_io(addr, data),
+            Region::TestMode(_) => {}
+            Region::Cartridge(addr) => self.mapper.cpu_write(addr, data),
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        let data = match Region::decode(addr) {
+            Region::Ram(offset) => Some(self.cpu_vram[offset as usize]),
+            Region::PpuRegister(register) => Some(self.ppu.peek_register(register)),
+            Region::ApuIo(addr) => self.read_apu_io(addr),
+            Region::TestMode(_) => None,
+            Region::Cartridge(addr) => self.mapper.cpu_read(addr),
+        };
+        data.unwrap_or(self.open_bus)
+    }
+
+    fn tick(&mut self, cycles: u16) {
+        // The PPU runs three dots for every CPU cycle
+        for _ in 0..cycles {
+            self.mapper.cpu_cycle();
+            self.apu.tick();
+            if let Some(addr) = self.apu.dmc_dma_address() {
+                let data = self.read(addr);
+                self.apu.load_dmc_sample(data);
+                self.dmc_dma_cycles += DMC_DMA_CYCLES;
+            }
+            for _ in 0..3 {
+                self.ppu.tick(self.mapper.as_mut());
+            }
+        }
+    }
+
+    // The copy is a read and a write per byte, after a cycle to halt the CPU
+    fn poll_dma(&mut self, odd_cycle: bool) -> u16 {
+        let mut cycles = std::mem::take(&mut self.dmc_dma_cycles);
+        if let Some(page) = self.oam_dma_page.take() {
+            let start = (page as u16) << 8;
+            for offset in 0..=0xFF {
+                let data = self.read(start | offset);
+                self.ppu
+                    .write_register(OAM_DATA, data, self.mapper.as_mut());
+            }
+            cycles += OAM_DMA_CYCLES + odd_cycle as u16;
+        }
+        cycles
+    }
+
+    fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
+    }
+
+    fn irq(&self) -> bool {
+        self.mapper.irq() || self.apu.irq()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bus, FlatRam, NesBus, Region};
+    use crate::{
+        cartridge::Cartridge,
+        controller::{ButtonState, FourScore, Port, Zapper},
+        error::EmulatorError,
+        mapper::Nrom,
+    };
+
+    #[test]
+    fn test_flat_ram_covers_whole_address_space() {
+        let mut ram = FlatRam::new();
+        ram.write(0xFFFF, 0x42);
+        assert_eq!(ram.read(0xFFFF), 0x42);
+    }
+
+    #[test]
+    fn test_nes_bus_mirrors_ram_and_prg_rom() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        bus.write(0x0001, 0x11);
+        assert_eq!(bus.read(0x0801), 0x11);
+        assert_eq!(bus.read(0x1801), 0x11);
+        assert_eq!(bus.read(0xC000), 0xEA);
+
+        bus.write(0x8000, 0x00);
+        assert_eq!(bus.read(0x8000), 0xEA);
+    }
+
+    #[test]
+    fn test_region_decoding_folds_mirrors() {
+        assert_eq!(Region::decode(0x07FF), Region::Ram(0x07FF));
+        assert_eq!(Region::decode(0x1000), Region::Ram(0x0000));
+        assert_eq!(Region::decode(0x2002), Region::PpuRegister(0x2002));
+        assert_eq!(Region::decode(0x3FFA), Region::PpuRegister(0x2002));
+        assert_eq!(Region::decode(0x4014), Region::ApuIo(0x4014));
+        assert_eq!(Region::decode(0x4017), Region::ApuIo(0x4017));
+        assert_eq!(Region::decode(0x401A), Region::TestMode(0x401A));
+        assert_eq!(Region::decode(0x4020), Region::Cartridge(0x4020));
+    }
+
+    #[test]
+    fn test_unmapped_reads_return_open_bus() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        bus.write(0x0010, 0x5A);
+        assert_eq!(bus.read(0x0010), 0x5A);
+        assert_eq!(bus.read(0x5000), 0x5A);
+
+        bus.write(0x6000, 0x33);
+        assert_eq!(bus.read(0x6000), 0x33);
+        assert_eq!(bus.read(0x4018), 0x33);
+    }
+
+    #[test]
+    fn test_oam_dma_copies_page_into_oam() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        for i in 0..=0xFF {
+            bus.write(0x0200 + i, i as u8);
+        }
+        // The copy starts wherever OAMADDR points and wraps around
+        bus.write(0x2003, 0x10);
+        bus.write(0x4014, 0x02);
+        assert_eq!(bus.ppu().oam[0], 0x00);
+
+        assert_eq!(bus.poll_dma(false), 513);
+        assert_eq!(bus.ppu().oam[0x10], 0x00);
+        assert_eq!(bus.ppu().oam[0xFF], 0xEF);
+        assert_eq!(bus.ppu().oam[0x00], 0xF0);
+        assert_eq!(bus.poll_dma(false), 0);
+
+        bus.write(0x4014, 0x02);
+        assert_eq!(bus.poll_dma(true), 514);
+    }
+
+    #[test]
+    fn test_dmc_sample_fetches_steal_cycles() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        // A one byte sample at $C000
+        bus.write(0x4012, 0x00);
+        bus.write(0x4013, 0x00);
+        bus.write(0x4015, 0b0001_0000);
+        assert_eq!(bus.read(0x4015) & 0b0001_0000, 0b0001_0000);
+
+        bus.tick(1);
+        assert_eq!(bus.read(0x4015) & 0b0001_0000, 0);
+        assert_eq!(bus.poll_dma(false), 4);
+        assert_eq!(bus.poll_dma(false), 0);
+    }
+
+    #[test]
+    fn test_apu_frame_irq_reaches_bus() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        for _ in 0..30000 / 250 {
+            bus.tick(250);
+        }
+        assert!(bus.irq());
+        assert_eq!(bus.peek(0x4015) & 0b0100_0000, 0b0100_0000);
+        bus.read(0x4015);
+        assert!(!bus.irq());
+    }
+
+    #[test]
+    fn test_joypads_through_4016_and_4017() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        bus.set_buttons(Port::One, ButtonState::B);
+        bus.set_buttons(Port::Two, ButtonState::A);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+
+        // The upper bits are whatever was last on the bus, which for LDA
+        // $4016 is the $40 from the high byte of the address
+        bus.write(0x0000, 0x40);
+        bus.read(0x0000);
+        assert_eq!(bus.read(0x4016), 0x40);
+        assert_eq!(bus.read(0x4016), 0x41);
+        assert_eq!(bus.peek(0x4017), 0x41);
+        assert_eq!(bus.read(0x4017), 0x41);
+        assert_eq!(bus.read(0x4017), 0x40);
+    }
+
+    #[test]
+    fn test_four_score_and_zapper_plug_into_ports() {
+        let mut bus = NesBus::new(Box::new(Nrom::new(vec![0xEA; 0x4000])));
+        bus.connect(Port::One, Box::new(FourScore::new(Port::One)));
+        bus.set_player_buttons(2, ButtonState::A);
+        bus.write(0x4016, 1);
+        bus.write(0x4016, 0);
+        let bits: Vec<u8> = (0..24).map(|_| bus.read(0x4016) & 1).collect();
+        assert_eq!(bits[8], 1);
+        assert_eq!(bits[19], 1);
+        assert_eq!(bits.iter().filter(|bit| **bit == 1).count(), 2);
+
+        bus.connect(Port::Two, Box::new(Zapper::new()));
+        bus.input_device_mut(Port::Two).set_aim(None, true);
+        assert_eq!(bus.read(0x4017) & 0b0001_1111, 0b0001_1000);
+    }
+
+    #[test]
+    fn test_nes_bus_from_cartridge_loads_trainer() {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0b0000_0100, 0];
+        raw.extend(vec![0; 8]);
+        raw.extend(vec![0x77; 512]);
+        raw.extend(vec![0xEA; 0x4000]);
+
+        let mut bus = NesBus::from_cartridge(Cartridge::from_bytes(&raw).unwrap()).unwrap();
+        assert_eq!(bus.read(0x7000), 0x77);
+        assert_eq!(bus.read(0x71FF), 0x77);
+        assert_eq!(bus.read(0x7200), 0x00);
+        assert_eq!(bus.read(0xFFFC), 0xEA);
+    }
+
+    #[test]
+    fn test_nes_bus_rejects_unknown_mapper() {
+        let cartridge = Cartridge::new(0xFF, vec![0; 0x4000], vec![]);
+        assert!(matches!(
+            NesBus::from_cartridge(cartridge),
+            Err(EmulatorError::UnsupportedMapper(0xFF))
+        ));
+    }
+
+    #[test]
+    fn test_battery_ram_round_trips_through_save_file() {
+        let path = std::env::temp_dir().join(format!("nes_lib_{}_test.sav", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut cartridge = Cartridge::new(1, vec![0; 0x8000], vec![]);
+        cartridge.battery = true;
+
+        let mut bus = NesBus::from_cartridge(cartridge.clone())
+            .unwrap()
+            .with_save_file(&path)
+            .unwrap();
+        bus.write(0x6000, 0x12);
+        bus.flush_save().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap()[0], 0x12);
+
+        // Dropping the bus flushes again
+        bus.write(0x6001, 0x34);
+        drop(bus);
+
+        let mut bus = NesBus::from_cartridge(cartridge)
+            .unwrap()
+            .with_save_file(&path)
+            .unwrap();
+        assert_eq!(bus.read(0x6000), 0x12);
+        assert_eq!(bus.read(0x6001), 0x34);
+
+        drop(bus);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_file_without_battery() {
+        let bus = NesBus::from_cartridge(Cartridge::new(0, vec![0; 0x4000], vec![]))
+            .unwrap()
+            .with_save_file("unused.sav")
+            .unwrap();
+        assert_eq!(bus.save_path(), None);
+    }
+}
@@ -0,0 +1,391 @@
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::error::EmulatorError;
+
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_ROM_PAGE_SIZE: usize = 0x4000;
+const CHR_ROM_PAGE_SIZE: usize = 0x2000;
+const DEFAULT_PRG_RAM_SIZE: usize = 0x2000;
+const DEFAULT_CHR_RAM_SIZE: usize = 0x2000;
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Serialize)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    /// Every nametable maps to the first 1KiB of VRAM
+    SingleScreenLower,
+    /// Every nametable maps to the second 1KiB of VRAM
+    SingleScreenUpper,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Eq, Serialize)]
+pub enum RomFormat {
+    INes,
+    Nes2,
+}
+
+/// A cartridge image parsed from an iNES or NES 2.0 file
+#[derive(Debug, Clone)]
+pub struct Cartridge {
+    pub format: RomFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: Option<Vec<u8>>,
+    pub prg_rom: Vec<u8>,
+    /// Empty when the board uses CHR RAM instead
+    pub chr_rom: Vec<u8>,
+    pub prg_ram_size: usize,
+    /// Battery backed PRG RAM, NES 2.0 keeps it separate from `prg_ram_size`
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+}
+
+impl Cartridge {
+    /// An iNES cartridge with horizontal mirroring and 8KiB of PRG RAM. An
+    /// empty `chr_rom` gives the board 8KiB of CHR RAM.
+    pub fn new(mapper: u16, prg_rom: Vec<u8>, chr_rom: Vec<u8>) -> Cartridge {
+        Cartridge {
+            format: RomFormat::INes,
+            mapper,
+            submapper: 0,
+            mirroring: Mirroring::Horizontal,
+            battery: false,
+            trainer: None,
+            chr_ram_size: if chr_rom.is_empty() {
+                DEFAULT_CHR_RAM_SIZE
+            } else {
+                0
+            },
+            prg_rom,
+            chr_rom,
+            prg_ram_size: DEFAULT_PRG_RAM_SIZE,
+            prg_nvram_size: 0,
+        }
+    }
+
+    pub fn from_file(file: &Path) -> Result<Cartridge, EmulatorError> {
+        let bytes = std::fs::read(file)?;
+        Cartridge::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Cartridge, EmulatorError> {
+        if raw.len() < HEADER_SIZE {
+            return Err(EmulatorError::TruncatedRom {
+                expected: HEADER_SIZE,
+                actual: raw.len(),
+            });
+        }
+        let header = &raw[..HEADER_SIZE];
+        if header[0..4] != NES_TAG {
+            return Err(EmulatorError::InvalidNesSignature);
+        }
+
+        let format = if header[7] & 0b0000_1100 == 0b0000_1000 {
+            RomFormat::Nes2
+        } else {
+            RomFormat::INes
+        };
+
+        let mirroring = if header[6] & 0b0000_1000 != 0 {
+            Mirroring::FourScreen
+        } else if header[6] & 0b0000_0001 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let battery = header[6] & 0b0000_0010 != 0;
+        let has_trainer = header[6] & 0b0000_0100 != 0;
+
+        let mut mapper = (header[6] >> 4) as u16;
+        let mut submapper = 0;
+        let prg_rom_size;
+        let chr_rom_size;
+        let prg_ram_size;
+        let prg_nvram_size;
+        let chr_ram_size;
+
+        match format {
+            RomFormat::Nes2 => {
+                mapper |= (header[7] & 0xF0) as u16;
+                mapper |= ((header[8] & 0x0F) as u16) << 8;
+                submapper = header[8] >> 4;
+                prg_rom_size = Self::nes2_rom_size(header[4], header[9] & 0x0F, PRG_ROM_PAGE_SIZE)?;
+                chr_rom_size = Self::nes2_rom_size(header[5], header[9] >> 4, CHR_ROM_PAGE_SIZE)?;
+                prg_ram_size = Self::nes2_ram_size(header[10] & 0x0F);
+                prg_nvram_size = Self::nes2_ram_size(header[10] >> 4);
+                chr_ram_size = Self::nes2_ram_size(header[11] & 0x0F);
+            }
+            RomFormat::INes => {
+                // Old dumps tagged by tools like "DiskDude!" have garbage in
+                // bytes 7-15, in that case only the low mapper nibble is real
+                if header[12..16].iter().all(|byte| *byte == 0) {
+                    mapper |= (header[7] & 0xF0) as u16;
+                }
+                prg_rom_size = header[4] as usize * PRG_ROM_PAGE_SIZE;
+                chr_rom_size = header[5] as usize * CHR_ROM_PAGE_SIZE;
+                // Byte 8 counts 8KiB units of PRG RAM, 0 means one unit
+                let ram_size = header[8].max(1) as usize * DEFAULT_PRG_RAM_SIZE;
+                if battery {
+                    prg_ram_size = 0;
+                    prg_nvram_size = ram_size;
+                } else {
+                    prg_ram_size = ram_size;
+                    prg_nvram_size = 0;
+                }
+                chr_ram_size = if chr_rom_size == 0 {
+                    DEFAULT_CHR_RAM_SIZE
+                } else {
+                    0
+                };
+            }
+        }
+
+        if prg_rom_size == 0 {
+            return Err(EmulatorError::InvalidRomHeader(String::from(
+                "PRG ROM size is zero",
+            )));
+        }
+
+        let trainer_size = if has_trainer { TRAINER_SIZE } else { 0 };
+        let expected = (HEADER_SIZE + trainer_size)
+            .checked_add(prg_rom_size)
+            .and_then(|size| size.checked_add(chr_rom_size))
+            .ok_or_else(|| {
+                EmulatorError::InvalidRomHeader(String::from(
+                    "ROM sizes overflow the address space",
+                ))
+            })?;
+        if raw.len() < expected {
+            return Err(EmulatorError::TruncatedRom {
+                expected,
+                actual: raw.len(),
+            });
+        }
+
+        let prg_rom_start = HEADER_SIZE + trainer_size;
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+
+        Ok(Cartridge {
+            format,
+            mapper,
+            submapper,
+            mirroring,
+            battery,
+            trainer: has_trainer.then(|| raw[HEADER_SIZE..prg_rom_start].to_vec()),
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+        })
+    }
+
+    // A size nibble of $F switches the LSB byte to an exponent-multiplier
+    // form, EEEEEEMM meaning 2^E * (MM * 2 + 1) bytes
+    fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, EmulatorError> {
+        if msb == 0x0F {
+            let exponent = (lsb >> 2) as u32;
+            let multiplier = (lsb & 0b11) as usize * 2 + 1;
+            return 1usize
+                .checked_shl(exponent)
+                .and_then(|size| size.checked_mul(multiplier))
+                .ok_or_else(|| {
+                    EmulatorError::InvalidRomHeader(format!(
+                        "ROM size 2^{} * {} is too large",
+                        exponent, multiplier
+                    ))
+                });
+        }
+        Ok((((msb as usize) << 8) | lsb as usize) * page_size)
+    }
+
+    // NES 2.0 RAM sizes are shift counts, 64 << n bytes, with 0 meaning none
+    fn nes2_ram_size(shift: u8) -> usize {
+        if shift == 0 {
+            0
+        } else {
+            64 << shift
+        }
+    }
+
+    /// All PRG RAM on the board, battery backed or not
+    pub fn total_prg_ram_size(&self) -> usize {
+        self.prg_ram_size + self.prg_nvram_size
+    }
+
+    /// Writes the cartridge out as an iNES file. ROM sizes are rounded down
+    /// to whole 16KiB PRG and 8KiB CHR pages.
+    pub fn to_ines(&self) -> Vec<u8> {
+        let prg_pages = (self.prg_rom.len() / PRG_ROM_PAGE_SIZE).min(0xFF);
+        let chr_pages = (self.chr_rom.len() / CHR_ROM_PAGE_SIZE).min(0xFF);
+
+        let mut flags_6 = ((self.mapper & 0x0F) as u8) << 4;
+        match self.mirroring {
+            Mirroring::Vertical => flags_6 |= 0b0000_0001,
+            Mirroring::FourScreen => flags_6 |= 0b0000_1000,
+            _ => {}
+        }
+        if self.battery {
+            flags_6 |= 0b0000_0010;
+        }
+        if self.trainer.is_some() {
+            flags_6 |= 0b0000_0100;
+        }
+        let flags_7 = (self.mapper & 0xF0) as u8;
+        let prg_ram_pages = (self.total_prg_ram_size() / DEFAULT_PRG_RAM_SIZE).min(0xFF);
+
+        let mut raw = NES_TAG.to_vec();
+        raw.extend([
+            prg_pages as u8,
+            chr_pages as u8,
+            flags_6,
+            flags_7,
+            prg_ram_pages as u8,
+        ]);
+        raw.resize(HEADER_SIZE, 0);
+        if let Some(trainer) = &self.trainer {
+            raw.extend(trainer);
+        }
+        raw.extend(&self.prg_rom[..prg_pages * PRG_ROM_PAGE_SIZE]);
+        raw.extend(&self.chr_rom[..chr_pages * CHR_ROM_PAGE_SIZE]);
+        raw
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cartridge, Mirroring, RomFormat};
+    use crate::error::EmulatorError;
+
+    fn header(bytes: [u8; 12]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A];
+        raw.extend_from_slice(&bytes);
+        raw
+    }
+
+    #[test]
+    fn test_parses_ines_header() {
+        let mut raw = header([2, 1, 0b0001_0011, 0b0100_0000, 0, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(vec![0xAA; 0x8000]);
+        raw.extend(vec![0xBB; 0x2000]);
+
+        let cartridge = Cartridge::from_bytes(&raw).unwrap();
+        assert_eq!(cartridge.format, RomFormat::INes);
+        assert_eq!(cartridge.mapper, 0x41);
+        assert_eq!(cartridge.mirroring, Mirroring::Vertical);
+        assert!(cartridge.battery);
+        assert_eq!(cartridge.prg_rom.len(), 0x8000);
+        assert_eq!(cartridge.chr_rom.len(), 0x2000);
+        assert_eq!(cartridge.prg_nvram_size, 0x2000);
+        assert_eq!(cartridge.chr_ram_size, 0);
+    }
+
+    #[test]
+    fn test_ines_ignores_garbage_in_later_bytes() {
+        let mut raw = header([1, 0, 0x10, 0x40, 0, 0, 0, 0, b'D', b'u', b'd', b'e']);
+        raw.extend(vec![0; 0x4000]);
+
+        let cartridge = Cartridge::from_bytes(&raw).unwrap();
+        assert_eq!(cartridge.mapper, 1);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn test_reads_trainer_before_prg_rom() {
+        let mut raw = header([1, 0, 0b0000_1100, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(vec![0x77; 512]);
+        raw.extend(vec![0x11; 0x4000]);
+
+        let cartridge = Cartridge::from_bytes(&raw).unwrap();
+        assert_eq!(cartridge.trainer, Some(vec![0x77; 512]));
+        assert_eq!(cartridge.prg_rom[0], 0x11);
+        assert_eq!(cartridge.mirroring, Mirroring::FourScreen);
+    }
+
+    #[test]
+    fn test_parses_nes2_header() {
+        // Mapper 0x104 submapper 3, PRG-RAM 8KiB, PRG-NVRAM 32KiB, CHR-RAM 8KiB
+        let mut raw = header([2, 0, 0x40, 0x08, 0x31, 0x00, 0x97, 0x07, 0, 0, 0, 0]);
+        raw.extend(vec![0; 0x8000]);
+
+        let cartridge = Cartridge::from_bytes(&raw).unwrap();
+        assert_eq!(cartridge.format, RomFormat::Nes2);
+        assert_eq!(cartridge.mapper, 0x104);
+        assert_eq!(cartridge.submapper, 3);
+        assert_eq!(cartridge.prg_ram_size, 0x2000);
+        assert_eq!(cartridge.prg_nvram_size, 0x8000);
+        assert_eq!(cartridge.chr_ram_size, 0x2000);
+    }
+
+    #[test]
+    fn test_nes2_exponent_multiplier_size() {
+        // 2^14 * 3 = 48KiB of PRG ROM
+        let mut raw = header([0b0011_1001, 0, 0, 0x08, 0, 0x0F, 0, 0, 0, 0, 0, 0]);
+        raw.extend(vec![0; 0xC000]);
+
+        let cartridge = Cartridge::from_bytes(&raw).unwrap();
+        assert_eq!(cartridge.prg_rom.len(), 0xC000);
+    }
+
+    #[test]
+    fn test_ines_round_trip() {
+        let mut cartridge = Cartridge::new(0x42, vec![0x11; 0x8000], vec![0x22; 0x2000]);
+        cartridge.mirroring = Mirroring::Vertical;
+        cartridge.battery = true;
+
+        let parsed = Cartridge::from_bytes(&cartridge.to_ines()).unwrap();
+        assert_eq!(parsed.mapper, 0x42);
+        assert_eq!(parsed.mirroring, Mirroring::Vertical);
+        assert!(parsed.battery);
+        assert_eq!(parsed.prg_rom, cartridge.prg_rom);
+        assert_eq!(parsed.chr_rom, cartridge.chr_rom);
+    }
+
+    #[test]
+    fn test_rejects_malformed_files() {
+        assert!(matches!(
+            Cartridge::from_bytes(&[0x4E, 0x45, 0x53]),
+            Err(EmulatorError::TruncatedRom { .. })
+        ));
+
+        let mut raw = header([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        raw[3] = 0x00;
+        assert!(matches!(
+            Cartridge::from_bytes(&raw),
+            Err(EmulatorError::InvalidNesSignature)
+        ));
+
+        let raw = header([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Cartridge::from_bytes(&raw),
+            Err(EmulatorError::InvalidRomHeader(_))
+        ));
+
+        let mut raw = header([2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        raw.extend(vec![0; 0x4000]);
+        assert!(matches!(
+            Cartridge::from_bytes(&raw),
+            Err(EmulatorError::TruncatedRom {
+                expected: 0x8010,
+                actual: 0x4010
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rejects_nes2_sizes_that_overflow() {
+        // 2^63 bytes each, which fit on their own but not added together
+        let raw = header([0b1111_1100, 0b1111_1100, 0, 0x08, 0, 0xFF, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            Cartridge::from_bytes(&raw),
+            Err(EmulatorError::InvalidRomHeader(_))
+        ));
+    }
+}
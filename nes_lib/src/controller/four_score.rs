@@ -0,0 +1,116 @@
+use crate::ppu::PPU;
+
+use super::{ButtonState, InputDevice, Port};
+
+// What the adapter sends after both controllers' buttons, so games can tell
+// it is plugged in. Port one sets the 20th read and port two the 19th.
+const PORT_ONE_SIGNATURE: u32 = 0b0000_1000;
+const PORT_TWO_SIGNATURE: u32 = 0b0000_0100;
+const BITS: u8 = 24;
+
+/// One side of a Four Score adapter. It takes two controllers per port,
+/// players 1 and 3 on port one and players 2 and 4 on port two, and shifts
+/// out both controllers then a signature on each port.
+#[derive(Debug)]
+pub struct FourScore {
+    buttons: [ButtonState; 2],
+    signature: u32,
+    strobe: bool,
+    shift_register: u32,
+    reads: u8,
+}
+
+impl FourScore {
+    pub fn new(port: Port) -> Self {
+        FourScore {
+            buttons: [ButtonState::empty(); 2],
+            signature: match port {
+                Port::One => PORT_ONE_SIGNATURE,
+                Port::Two => PORT_TWO_SIGNATURE,
+            },
+            strobe: false,
+            shift_register: 0,
+            reads: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift_register = self.buttons[0].bits() as u32
+            | (self.buttons[1].bits() as u32) << 8
+            | self.signature << 16;
+        self.reads = 0;
+    }
+}
+
+impl InputDevice for FourScore {
+    fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, ppu: &PPU) -> u8 {
+        let data = self.peek(ppu);
+        if !self.strobe && self.reads < BITS {
+            self.shift_register >>= 1;
+            self.reads += 1;
+        }
+        data
+    }
+
+    fn peek(&self, _ppu: &PPU) -> u8 {
+        if self.strobe {
+            self.buttons[0].contains(ButtonState::A) as u8
+        } else if self.reads >= BITS {
+            1
+        } else {
+            (self.shift_register & 1) as u8
+        }
+    }
+
+    /// Player 0 is the controller read first, player 1 the one after it
+    fn set_buttons(&mut self, player: usize, buttons: ButtonState) {
+        if let Some(slot) = self.buttons.get_mut(player) {
+            *slot = buttons;
+        }
+        if self.strobe {
+            self.latch();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FourScore;
+    use crate::{
+        controller::{ButtonState, InputDevice, Port},
+        ppu::PPU,
+    };
+
+    fn read_all(four_score: &mut FourScore) -> Vec<u8> {
+        let ppu = PPU::new();
+        four_score.write_strobe(true);
+        four_score.write_strobe(false);
+        (0..25).map(|_| four_score.read(&ppu)).collect()
+    }
+
+    #[test]
+    fn test_shifts_both_controllers_then_signature() {
+        let mut four_score = FourScore::new(Port::One);
+        four_score.set_buttons(0, ButtonState::A);
+        four_score.set_buttons(1, ButtonState::B | ButtonState::RIGHT);
+
+        let bits = read_all(&mut four_score);
+        assert_eq!(bits[..8], [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(bits[8..16], [0, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(bits[16..24], [0, 0, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(bits[24], 1);
+    }
+
+    #[test]
+    fn test_port_two_signature() {
+        let bits = read_all(&mut FourScore::new(Port::Two));
+        assert_eq!(bits[16..24], [0, 0, 1, 0, 0, 0, 0, 0]);
+    }
+}
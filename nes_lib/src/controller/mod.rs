@@ -0,0 +1,187 @@
+use std::fmt::Debug;
+
+use bitflags::bitflags;
+
+use crate::ppu::PPU;
+
+pub use self::{four_score::FourScore, zapper::Zapper};
+
+mod four_score;
+mod zapper;
+
+bitflags! {
+    /// The buttons on a standard controller, in the order it shifts them out
+    #[derive(Default)]
+    pub struct ButtonState: u8 {
+        const A = 0b0000_0001;
+        const B = 0b0000_0010;
+        const SELECT = 0b0000_0100;
+        const START = 0b0000_1000;
+        const UP = 0b0001_0000;
+        const DOWN = 0b0010_0000;
+        const LEFT = 0b0100_0000;
+        const RIGHT = 0b1000_0000;
+    }
+}
+
+/// The two controller ports on the front of the console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    /// Read through $4016
+    One,
+    /// Read through $4017
+    Two,
+}
+
+impl Port {
+    pub fn index(&self) -> usize {
+        match self {
+            Port::One => 0,
+            Port::Two => 1,
+        }
+    }
+}
+
+/// Anything that plugs into a controller port. Writes to $4016 drive the
+/// strobe line of both ports, and reads of $4016 or $4017 see the data lines
+/// of one port in their low five bits.
+pub trait InputDevice: Debug {
+    fn write_strobe(&mut self, strobe: bool);
+
+    /// Reads the data lines, clocking the device. The PPU is there for light
+    /// guns to look at the picture.
+    fn read(&mut self, ppu: &PPU) -> u8;
+
+    /// Reads the data lines without clocking the device
+    fn peek(&self, ppu: &PPU) -> u8;
+
+    /// Holds down buttons on the `player`th controller plugged in through
+    /// this device. Devices without buttons ignore this.
+    fn set_buttons(&mut self, _player: usize, _buttons: ButtonState) {}
+
+    /// Points a light gun at a pixel on the screen, or away from it with
+    /// `None`, and holds or releases its trigger. Everything else ignores
+    /// this.
+    fn set_aim(&mut self, _aim: Option<(usize, usize)>, _trigger: bool) {}
+}
+
+/// A standard controller. Writing 1 to $4016 latches the buttons, and after
+/// it goes back to 0 each read shifts out the next one.
+#[derive(Debug, Default)]
+pub struct Joypad {
+    buttons: ButtonState,
+    strobe: bool,
+    shift_register: u8,
+    // Once all eight buttons are out, the register has filled with 1s
+    reads: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn buttons(&self) -> ButtonState {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: ButtonState) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    pub fn write_strobe(&mut self, strobe: bool) {
+        self.strobe = strobe;
+        if strobe {
+            self.latch();
+        }
+    }
+
+    fn latch(&mut self) {
+        self.shift_register = self.buttons.bits();
+        self.reads = 0;
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let data = self.peek();
+        // While the strobe is held the register keeps reloading, so reads
+        // keep returning A
+        if !self.strobe && self.reads < 8 {
+            self.shift_register >>= 1;
+            self.reads += 1;
+        }
+        data
+    }
+
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons.contains(ButtonState::A) as u8
+        } else if self.reads >= 8 {
+            1
+        } else {
+            self.shift_register & 1
+        }
+    }
+}
+
+impl InputDevice for Joypad {
+    fn write_strobe(&mut self, strobe: bool) {
+        Joypad::write_strobe(self, strobe);
+    }
+
+    fn read(&mut self, _ppu: &PPU) -> u8 {
+        Joypad::read(self)
+    }
+
+    fn peek(&self, _ppu: &PPU) -> u8 {
+        Joypad::peek(self)
+    }
+
+    fn set_buttons(&mut self, player: usize, buttons: ButtonState) {
+        if player == 0 {
+            Joypad::set_buttons(self, buttons);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ButtonState, Joypad};
+
+    #[test]
+    fn test_shifts_buttons_in_order() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(ButtonState::A | ButtonState::START | ButtonState::RIGHT);
+        joypad.write_strobe(true);
+        joypad.write_strobe(false);
+
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        // Official controllers return 1 after the eighth read
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_held_strobe_keeps_reading_a() {
+        let mut joypad = Joypad::new();
+        joypad.write_strobe(true);
+        assert_eq!(joypad.read(), 0);
+        joypad.set_buttons(ButtonState::A);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+    }
+
+    #[test]
+    fn test_buttons_latch_on_strobe() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(ButtonState::B);
+        joypad.write_strobe(true);
+        joypad.write_strobe(false);
+        // Changes after the latch wait for the next strobe
+        joypad.set_buttons(ButtonState::A);
+        assert_eq!(joypad.read(), 0);
+        assert_eq!(joypad.peek(), 1);
+        assert_eq!(joypad.read(), 1);
+    }
+}
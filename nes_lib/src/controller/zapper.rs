@@ -0,0 +1,132 @@
+use crate::ppu::{Palette, PPU, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use super::InputDevice;
+
+const LIGHT_NOT_SENSED: u8 = 0b0000_1000;
+const TRIGGER_PULLED: u8 = 0b0001_0000;
+// The photodiode keeps reporting light for a while after the beam passes
+const LIGHT_SENSE_SCANLINES: usize = 26;
+// How bright, from 0 to 255, a pixel has to be to set the sensor off
+const BRIGHTNESS_THRESHOLD: f32 = 128.0;
+
+/// The Zapper light gun. It has no shift register, it just reports whether
+/// the trigger is held and whether the spot on the screen it is aimed at has
+/// just been drawn in a bright color.
+#[derive(Debug)]
+pub struct Zapper {
+    aim: Option<(usize, usize)>,
+    trigger: bool,
+    palette: Palette,
+}
+
+impl Default for Zapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Zapper {
+            aim: None,
+            trigger: false,
+            palette: Palette::ntsc(),
+        }
+    }
+
+    fn senses_light(&self, ppu: &PPU) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+        if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+            return false;
+        }
+        // Only a spot the beam has drawn in the last few scanlines glows
+        let scanline = ppu.scanline as usize;
+        let drawn = scanline > y || (scanline == y && ppu.dot as usize > x);
+        if !drawn || scanline - y > LIGHT_SENSE_SCANLINES {
+            return false;
+        }
+        let [r, g, b] = self.palette.rgb(ppu.pixels()[y * SCREEN_WIDTH + x]);
+        0.299 * r as f32 + 0.587 * g as f32 + 0.114 * b as f32 >= BRIGHTNESS_THRESHOLD
+    }
+}
+
+impl InputDevice for Zapper {
+    fn write_strobe(&mut self, _strobe: bool) {}
+
+    fn read(&mut self, ppu: &PPU) -> u8 {
+        self.peek(ppu)
+    }
+
+    fn peek(&self, ppu: &PPU) -> u8 {
+        let mut data = 0;
+        if !self.senses_light(ppu) {
+            data |= LIGHT_NOT_SENSED;
+        }
+        if self.trigger {
+            data |= TRIGGER_PULLED;
+        }
+        data
+    }
+
+    fn set_aim(&mut self, aim: Option<(usize, usize)>, trigger: bool) {
+        self.aim = aim;
+        self.trigger = trigger;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Zapper;
+    use crate::{controller::InputDevice, mapper::Nrom, ppu::PPU};
+
+    // A PPU with rendering off, drawing a white backdrop down to `scanline`
+    fn white_screen(scanline: u16) -> PPU {
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        let mut ppu = PPU::new();
+        for data in [0x3F, 0x00] {
+            ppu.write_register(0x2006, data, &mut mapper);
+        }
+        ppu.write_register(0x2007, 0x30, &mut mapper);
+        while ppu.scanline != scanline {
+            ppu.tick(&mut mapper);
+        }
+        ppu
+    }
+
+    #[test]
+    fn test_senses_recently_drawn_bright_pixels() {
+        let ppu = white_screen(100);
+        let mut zapper = Zapper::new();
+
+        zapper.set_aim(Some((128, 90)), false);
+        assert_eq!(zapper.read(&ppu), 0b0000_0000);
+        // Drawn too long ago
+        zapper.set_aim(Some((128, 50)), false);
+        assert_eq!(zapper.read(&ppu), 0b0000_1000);
+        // Not drawn yet this frame
+        zapper.set_aim(Some((128, 150)), true);
+        assert_eq!(zapper.read(&ppu), 0b0001_1000);
+        // Off screen
+        zapper.set_aim(None, true);
+        assert_eq!(zapper.read(&ppu), 0b0001_1000);
+    }
+
+    #[test]
+    fn test_dark_pixels_are_not_sensed() {
+        let mut ppu = white_screen(100);
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        for data in [0x3F, 0x00] {
+            ppu.write_register(0x2006, data, &mut mapper);
+        }
+        ppu.write_register(0x2007, 0x0F, &mut mapper);
+        while ppu.scanline != 110 {
+            ppu.tick(&mut mapper);
+        }
+
+        let mut zapper = Zapper::new();
+        zapper.set_aim(Some((128, 105)), false);
+        assert_eq!(zapper.read(&ppu), 0b0000_1000);
+    }
+}
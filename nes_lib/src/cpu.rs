@@ -1,195 +1,1868 @@
+use std::{collections::HashSet, fmt, path::Path};
+
+use bitflags::bitflags;
+use serde::{Serialize, Serializer};
 use serde_json::json;
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::{
+    bus::{Bus, FlatRam, NesBus},
+    cartridge::Cartridge,
+    error::EmulatorError,
+    instructions::{AddressMode, OpCode, OPCODES_MAP, UNOFFICIAL_OPCODES_MAP},
+};
+
+bitflags! {
+    /// The processor status register
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  N V U B D I Z C
+    pub struct StatusFlags: u8 {
+        const CARRY             = 0b0000_0001;
+        const ZERO              = 0b0000_0010;
+        const INTERRUPT_DISABLE = 0b0000_0100;
+        const DECIMAL_MODE      = 0b0000_1000;
+        const BREAK             = 0b0001_0000;
+        const UNUSED            = 0b0010_0000;
+        const OVERFLOW          = 0b0100_0000;
+        const NEGATIVE          = 0b1000_0000;
+    }
+}
+
+impl StatusFlags {
+    // Ordered from bit 7 down to bit 0, the way debuggers print them
+    const NAMES: [(StatusFlags, char); 8] = [
+        (StatusFlags::NEGATIVE, 'N'),
+        (StatusFlags::OVERFLOW, 'V'),
+        (StatusFlags::UNUSED, 'U'),
+        (StatusFlags::BREAK, 'B'),
+        (StatusFlags::DECIMAL_MODE, 'D'),
+        (StatusFlags::INTERRUPT_DISABLE, 'I'),
+        (StatusFlags::ZERO, 'Z'),
+        (StatusFlags::CARRY, 'C'),
+    ];
+
+    pub fn update_zero_and_negative(&mut self, result: u8) {
+        self.set(StatusFlags::ZERO, result == 0);
+        self.set(StatusFlags::NEGATIVE, result & 0b1000_0000 != 0);
+    }
+
+    /// The names of the flags that are set, e.g. `["N", "I", "Z"]`
+    pub fn flag_names(&self) -> Vec<String> {
+        StatusFlags::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+/// Formats as `NVUBDIZC`, with cleared flags in lower case
+impl fmt::Display for StatusFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (flag, name) in StatusFlags::NAMES.iter() {
+            if self.contains(*flag) {
+                write!(f, "{}", name)?;
+            } else {
+                write!(f, "{}", name.to_ascii_lowercase())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for StatusFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+const STACK: u16 = 0x0100;
+const STACK_RESET: u8 = 0xFD;
+
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+impl Interrupt {
+    fn vector(&self) -> u16 {
+        match self {
+            Interrupt::Nmi => NMI_VECTOR,
+            Interrupt::Irq | Interrupt::Brk => IRQ_VECTOR,
+        }
+    }
+}
+
+/// The effective operand of an instruction once its addressing mode is resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Implied,
+    Accumulator,
+    Address(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResolvedOperand {
+    pub operand: Operand,
+    /// Whether indexing (or a branch) moved the address onto another page
+    pub page_crossed: bool,
+}
+
+/// An instruction executed by `CPU::step`
+#[derive(Debug, Clone, Copy)]
+pub struct Step {
+    /// Where the opcode was fetched from
+    pub address: u16,
+    pub opcode: u8,
+    /// `None` when the byte was not a known instruction and the illegal
+    /// opcode policy skipped it or jammed the CPU
+    pub instruction: Option<&'static OpCode>,
+    /// Cycles consumed, including any interrupt serviced before it and any
+    /// DMA it started
+    pub cycles: u64,
+}
+
+/// What the CPU does when it fetches a byte that isn't a known instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IllegalOpcodePolicy {
+    /// Return `EmulatorError::IllegalOpcode`, leaving the PC on the opcode
+    #[default]
+    Halt,
+    /// Treat the byte as a single byte, 2 cycle NOP
+    Nop,
+    /// Lock up like the real 6502 does until the next reset
+    Jam,
+}
+
+/// Why one of the `run` functions handed control back to the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Brk,
+    Breakpoint(u16),
+    /// The CPU jammed on this opcode and won't run again until reset
+    IllegalOpcode {
+        opcode: u8,
+        address: u16,
+    },
+    CycleBudget,
+    FrameBudget,
+    Predicate,
+}
 
 #[derive(Debug)]
-pub struct CPU {
+pub struct CPU<B: Bus = FlatRam> {
     pub register_a: u8,
     pub register_x: u8,
     pub register_y: u8,
-    pub status: u8,
+    pub status: StatusFlags,
     pub program_counter: u16,
-    pub memory: Vec<u8>,
+    pub stack_pointer: u8,
+    /// Total CPU cycles executed since power on
+    pub cycles: u64,
+    pub bus: B,
     pub is_running: bool,
     pub debug_mode: bool,
+    /// Stop running after a BRK has entered its handler instead of continuing
+    pub halt_on_brk: bool,
+    pub breakpoints: HashSet<u16>,
+    /// Execute the stable undocumented opcodes, in strict mode they go
+    /// through the illegal opcode policy instead
+    pub unofficial_opcodes: bool,
+    pub illegal_opcode_policy: IllegalOpcodePolicy,
+    pub jammed: bool,
+    pub nmi_pending: bool,
+    /// The IRQ line is level triggered, devices hold it until acknowledged.
+    /// Devices on the bus can also pull it through `Bus::irq`.
+    pub irq_line: bool,
     pub monitored_memory_range: (usize, usize),
 }
 
-impl CPU {
+impl Default for CPU<FlatRam> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CPU<FlatRam> {
     pub fn new() -> Self {
+        Self::with_bus(FlatRam::new())
+    }
+
+    /// Copies a raw program to $8000 and points the reset vector at it
+    pub fn load(&mut self, program: Vec<u8>) {
+        self.bus.load(0x8000, &program);
+        self.mem_write_u16(RESET_VECTOR, 0x8000);
+    }
+
+    pub fn load_and_run(&mut self, program: Vec<u8>) -> Result<StopReason, EmulatorError> {
+        self.load(program);
+        self.reset();
+        self.run()
+    }
+}
+
+impl CPU<NesBus> {
+    /// Plugs a cartridge into a fresh console and resets the CPU
+    pub fn from_cartridge(cartridge: Cartridge) -> Result<Self, EmulatorError> {
+        let mut cpu = Self::with_bus(NesBus::from_cartridge(cartridge)?);
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    /// Loads a ROM file the same way, with battery saves next to the ROM
+    pub fn from_rom_file(path: &Path) -> Result<Self, EmulatorError> {
+        let mut cpu = Self::with_bus(NesBus::from_rom_file(path)?);
+        cpu.reset();
+        Ok(cpu)
+    }
+
+    /// Runs until the PPU has started `frames` more frames
+    pub fn run_frames(&mut self, frames: u64) -> Result<StopReason, EmulatorError> {
+        let target = self.bus.ppu().frame + frames;
+        match self.run_until(|cpu| cpu.bus.ppu().frame >= target)? {
+            StopReason::Predicate => Ok(StopReason::FrameBudget),
+            reason => Ok(reason),
+        }
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    pub fn with_bus(bus: B) -> Self {
         CPU {
             register_a: 0,
             register_x: 0,
             register_y: 0,
-            status: 0,
+            status: StatusFlags::empty(),
             program_counter: 0,
-            memory: vec![0; 0xFFFF],
+            stack_pointer: STACK_RESET,
+            cycles: 0,
+            bus,
             is_running: false,
             debug_mode: false,
+            halt_on_brk: true,
+            breakpoints: HashSet::new(),
+            unofficial_opcodes: true,
+            illegal_opcode_policy: IllegalOpcodePolicy::default(),
+            jammed: false,
+            nmi_pending: false,
+            irq_line: false,
             monitored_memory_range: (0x0000, 15),
         }
     }
 
-    fn mem_read(&self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.bus.read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
-        self.memory[addr as usize] = data;
+        self.bus.write(addr, data);
     }
 
     fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
-        let hi = self.mem_read(pos + 1) as u16;
-        (hi << 8) | (lo as u16)
+        let hi = self.mem_read(pos.wrapping_add(1)) as u16;
+        (hi << 8) | lo
     }
 
     fn mem_write_u16(&mut self, pos: u16, data: u16) {
         let hi = (data >> 8) as u8;
         let lo = (data & 0xff) as u8;
         self.mem_write(pos, lo);
-        self.mem_write(pos + 1, hi);
+        self.mem_write(pos.wrapping_add(1), hi);
     }
 
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
-        self.status = 0;
+        self.status = StatusFlags::INTERRUPT_DISABLE | StatusFlags::UNUSED;
+        self.stack_pointer = STACK_RESET;
+        self.nmi_pending = false;
+        self.jammed = false;
 
-        self.program_counter = self.mem_read_u16(0xFFFC);
+        self.program_counter = self.mem_read_u16(RESET_VECTOR);
+        // The reset sequence takes 7 cycles before the first instruction
+        self.cycles = 7;
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        self.memory[0x8000..(0x8000 + program.len())].copy_from_slice(&program[..]);
+    fn stack_push(&mut self, data: u8) {
+        self.mem_write(STACK + self.stack_pointer as u16, data);
+        self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
-        self.reset();
-        self.run();
+    fn stack_pop(&mut self) -> u8 {
+        self.stack_pointer = self.stack_pointer.wrapping_add(1);
+        self.mem_read(STACK + self.stack_pointer as u16)
     }
 
-    fn lda(&mut self, value: u8) {
-        self.register_a = value;
-        self.update_zero_and_negative_flags(self.register_a);
+    fn stack_push_u16(&mut self, data: u16) {
+        self.stack_push((data >> 8) as u8);
+        self.stack_push((data & 0xFF) as u8);
     }
 
-    fn tax(&mut self) {
-        self.register_x = self.register_a;
-        self.update_zero_and_negative_flags(self.register_x);
+    fn stack_pop_u16(&mut self) -> u16 {
+        let lo = self.stack_pop() as u16;
+        let hi = self.stack_pop() as u16;
+        (hi << 8) | lo
     }
 
-    fn sta(&mut self, value: u8) {
-        self.memory[value as usize] = self.register_a;
+    /// Latches a non-maskable interrupt, serviced before the next instruction
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
     }
 
-    fn update_zero_and_negative_flags(&mut self, result: u8) {
-        if result == 0 {
-            self.status = self.status | 0b0000_0010;
-        } else {
-            self.status = self.status & 0b1111_1101;
+    pub fn set_irq(&mut self, active: bool) {
+        self.irq_line = active;
+    }
+
+    // Pushes the return address and status, then jumps through the vector.
+    // The B flag only exists on the stack and is set for BRK/PHP pushes only.
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        self.stack_push_u16(self.program_counter);
+        let mut flags = self.status | StatusFlags::UNUSED;
+        flags.set(StatusFlags::BREAK, interrupt == Interrupt::Brk);
+        self.stack_push(flags.bits());
+        self.set_flag(StatusFlags::INTERRUPT_DISABLE, true);
+        self.program_counter = self.mem_read_u16(interrupt.vector());
+
+        // BRK's 7 cycles are counted from its opcode entry
+        if interrupt != Interrupt::Brk {
+            self.cycles += 7;
         }
+    }
 
-        if result & 0b1000_0000 != 0 {
-            self.status = self.status | 0b1000_0000;
-        } else {
-            self.status = self.status & 0b0111_1111;
+    fn poll_interrupts(&mut self) {
+        if self.bus.poll_nmi() {
+            self.nmi_pending = true;
+        }
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(Interrupt::Nmi);
+        } else if (self.irq_line || self.bus.irq())
+            && !self.status.contains(StatusFlags::INTERRUPT_DISABLE)
+        {
+            self.interrupt(Interrupt::Irq);
         }
     }
 
-    pub fn run(&mut self) {
-        info!("Starting to interpret bytes");
-        self.is_running = true;
+    fn page_crossed(a: u16, b: u16) -> bool {
+        a & 0xFF00 != b & 0xFF00
+    }
 
-        loop {
-            let opscode = self.mem_read(self.program_counter);
-            self.program_counter += 1;
-
-            match opscode {
-                0xA9 => {
-                    let param = self.mem_read(self.program_counter);
-                    self.program_counter += 1;
-                    self.lda(param);
-                }
-                0xAA => self.tax(),
-                0x85 => {
-                    let param = self.mem_read(self.program_counter);
-                    self.program_counter += 1;
-                    self.sta(param);
-                }
-                0x00 => {
-                    break;
-                }
-                _ => todo!(""),
+    // Reads a pointer stored in the zero page, the high byte wraps back to $00
+    fn read_zero_page_pointer(&mut self, ptr: u8) -> u16 {
+        let lo = self.mem_read(ptr as u16) as u16;
+        let hi = self.mem_read(ptr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    /// Resolves the operand of the instruction whose operand bytes start at the
+    /// program counter, without consuming them
+    pub fn resolve_operand(&mut self, mode: AddressMode) -> ResolvedOperand {
+        let pc = self.program_counter;
+        let (operand, page_crossed) = match mode {
+            AddressMode::Implied => (Operand::Implied, false),
+            AddressMode::Accumulator => (Operand::Accumulator, false),
+            AddressMode::Immediate => (Operand::Address(pc), false),
+            AddressMode::ZeroPage => (Operand::Address(self.mem_read(pc) as u16), false),
+            AddressMode::ZeroPageX => {
+                let pos = self.mem_read(pc).wrapping_add(self.register_x);
+                (Operand::Address(pos as u16), false)
             }
+            AddressMode::ZeroPageY => {
+                let pos = self.mem_read(pc).wrapping_add(self.register_y);
+                (Operand::Address(pos as u16), false)
+            }
+            AddressMode::Absolute => (Operand::Address(self.mem_read_u16(pc)), false),
+            AddressMode::AbsoluteX => {
+                let base = self.mem_read_u16(pc);
+                let addr = base.wrapping_add(self.register_x as u16);
+                (Operand::Address(addr), Self::page_crossed(base, addr))
+            }
+            AddressMode::AbsoluteY => {
+                let base = self.mem_read_u16(pc);
+                let addr = base.wrapping_add(self.register_y as u16);
+                (Operand::Address(addr), Self::page_crossed(base, addr))
+            }
+            AddressMode::Indirect => {
+                // The 6502 never carries into the high byte of the pointer, so
+                // JMP ($10FF) reads its target from $10FF and $1000
+                let ptr = self.mem_read_u16(pc);
+                let lo = self.mem_read(ptr) as u16;
+                let hi = self.mem_read((ptr & 0xFF00) | (ptr.wrapping_add(1) & 0x00FF)) as u16;
+                (Operand::Address((hi << 8) | lo), false)
+            }
+            AddressMode::IndirectX => {
+                let ptr = self.mem_read(pc).wrapping_add(self.register_x);
+                (Operand::Address(self.read_zero_page_pointer(ptr)), false)
+            }
+            AddressMode::IndirectY => {
+                let ptr = self.mem_read(pc);
+                let base = self.read_zero_page_pointer(ptr);
+                let addr = base.wrapping_add(self.register_y as u16);
+                (Operand::Address(addr), Self::page_crossed(base, addr))
+            }
+            AddressMode::Relative => {
+                let offset = self.mem_read(pc) as i8;
+                let next = pc.wrapping_add(1);
+                let target = next.wrapping_add(offset as u16);
+                (Operand::Address(target), Self::page_crossed(next, target))
+            }
+        };
+
+        ResolvedOperand {
+            operand,
+            page_crossed,
         }
+    }
 
-        self.is_running = false;
+    fn read_operand(&mut self, operand: Operand) -> u8 {
+        match operand {
+            Operand::Accumulator => self.register_a,
+            Operand::Address(addr) => self.mem_read(addr),
+            Operand::Implied => panic!("Implied instructions do not have an operand to read"),
+        }
     }
 
-    pub fn to_cpu_json(&self) -> String {
-        let mut monitored_memory: Vec<(String, Vec<u8>)> = Vec::new();
-        for i in 0..self.monitored_memory_range.1 {
-            let start_index = (self.monitored_memory_range.0 + i * 64) as usize;
-            let end_index = (self.monitored_memory_range.0 + (i + 1) * 64) as usize;
-            if start_index < self.memory.len() {
-                let chunk = if end_index <= self.memory.len() {
-                    self.memory[start_index..end_index].to_vec()
-                } else {
-                    self.memory[start_index..].to_vec()
-                };
-                let address = format!("{:02X}", start_index);
-                monitored_memory.push(("0x".to_owned() + &address, chunk));
-            }
+    fn write_operand(&mut self, operand: Operand, data: u8) {
+        match operand {
+            Operand::Accumulator => self.register_a = data,
+            Operand::Address(addr) => self.mem_write(addr, data),
+            Operand::Implied => panic!("Implied instructions do not have an operand to write"),
         }
+    }
 
-        let cpu_state = json!({
-            "register_a": self.register_a,
-            "register_x": self.register_x,
-            "register_y": self.register_y,
-            "status": self.status,
-            "program_counter": self.program_counter,
-            "memory": monitored_memory,
-            "is_running": self.is_running,
-            "debug_mode": self.debug_mode
-        });
+    fn operand_address(operand: Operand) -> u16 {
+        match operand {
+            Operand::Address(addr) => addr,
+            _ => panic!("{:?} does not have an effective address", operand),
+        }
+    }
 
-        cpu_state.to_string()
+    fn set_flag(&mut self, flag: StatusFlags, value: bool) {
+        self.status.set(flag, value);
     }
 
-    pub fn set_memory_start_address(&mut self, start: usize) {
-        self.monitored_memory_range = (start, 10);
+    fn update_zero_and_negative_flags(&mut self, result: u8) {
+        self.status.update_zero_and_negative(result);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::CPU;
+    fn set_register_a(&mut self, value: u8) {
+        self.register_a = value;
+        self.update_zero_and_negative_flags(self.register_a);
+    }
 
-    #[test]
-    fn test_0xa9_lda_immediate_load_data() {
-        let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xA9, 0x05, 0x00]);
-        assert_eq!(cpu.register_a, 0x05);
-        assert!(cpu.status & 0b0000_0010 == 0b00);
-        assert!(cpu.status & 0b1000_0000 == 0);
+    // The NES' 2A03 has no decimal mode, so ADC and SBC are always binary
+    fn add_to_register_a(&mut self, data: u8) {
+        let carry = self.status.contains(StatusFlags::CARRY) as u16;
+        let sum = self.register_a as u16 + data as u16 + carry;
+        let result = sum as u8;
+
+        self.set_flag(StatusFlags::CARRY, sum > 0xFF);
+        self.set_flag(
+            StatusFlags::OVERFLOW,
+            (data ^ result) & (result ^ self.register_a) & 0x80 != 0,
+        );
+        self.set_register_a(result);
     }
 
-    #[test]
-    fn test_0xa9_lda_zero_flag() {
-        let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xA9, 0x00, 0x00]);
-        assert_eq!(cpu.register_a, 0);
-        assert!(cpu.status & 0b0000_0010 == 0b10);
-        assert!(cpu.status & 0b1000_0000 == 0);
+    fn compare(&mut self, operand: Operand, compare_with: u8) {
+        let data = self.read_operand(operand);
+        self.compare_value(data, compare_with);
     }
 
-    #[test]
-    fn test_0xa9_lda_negative_flag() {
-        let mut cpu = CPU::new();
-        cpu.load_and_run(vec![0xA9, 0x80, 0x00]);
-        assert_eq!(cpu.register_a, 0x80);
-        assert!(cpu.status & 0b0000_0010 == 0b00);
-        assert!(cpu.status & 0b1000_0000 == 0b1000_0000);
+    fn compare_value(&mut self, data: u8, compare_with: u8) {
+        self.set_flag(StatusFlags::CARRY, compare_with >= data);
+        self.update_zero_and_negative_flags(compare_with.wrapping_sub(data));
+    }
+
+    // A taken branch costs one more cycle, and another if it lands on a new page
+    fn branch(&mut self, resolved: ResolvedOperand, condition: bool) {
+        if condition {
+            self.cycles += if resolved.page_crossed { 2 } else { 1 };
+            self.program_counter = Self::operand_address(resolved.operand);
+        }
+    }
+
+    fn adc(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        self.add_to_register_a(value);
+    }
+
+    fn sbc(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        // A - M - (1 - C) is the same as A + !M + C
+        self.add_to_register_a(!value);
+    }
+
+    fn and(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        self.set_register_a(self.register_a & value);
+    }
+
+    fn eor(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        self.set_register_a(self.register_a ^ value);
+    }
+
+    fn ora(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        self.set_register_a(self.register_a | value);
+    }
+
+    // Read-modify-write instructions read their operand once, write it back
+    // unchanged while they work on it like the 6502 does, then write the
+    // result. The result is returned for the unofficial opcodes that go on
+    // to combine it with A.
+    fn modify<F>(&mut self, operand: Operand, op: F) -> u8
+    where
+        F: FnOnce(&mut Self, u8) -> u8,
+    {
+        let data = self.read_operand(operand);
+        self.write_operand(operand, data);
+        let result = op(self, data);
+        self.write_operand(operand, result);
+        self.update_zero_and_negative_flags(result);
+        result
+    }
+
+    fn shift_left(&mut self, data: u8) -> u8 {
+        self.set_flag(StatusFlags::CARRY, data & 0b1000_0000 != 0);
+        data << 1
+    }
+
+    fn shift_right(&mut self, data: u8) -> u8 {
+        self.set_flag(StatusFlags::CARRY, data & 0b0000_0001 != 0);
+        data >> 1
+    }
+
+    fn rotate_left(&mut self, data: u8) -> u8 {
+        let old_carry = self.status.contains(StatusFlags::CARRY) as u8;
+        self.set_flag(StatusFlags::CARRY, data & 0b1000_0000 != 0);
+        (data << 1) | old_carry
+    }
+
+    fn rotate_right(&mut self, data: u8) -> u8 {
+        let old_carry = self.status.contains(StatusFlags::CARRY) as u8;
+        self.set_flag(StatusFlags::CARRY, data & 0b0000_0001 != 0);
+        (data >> 1) | (old_carry << 7)
+    }
+
+    fn asl(&mut self, operand: Operand) {
+        self.modify(operand, Self::shift_left);
+    }
+
+    fn lsr(&mut self, operand: Operand) {
+        self.modify(operand, Self::shift_right);
+    }
+
+    fn rol(&mut self, operand: Operand) {
+        self.modify(operand, Self::rotate_left);
+    }
+
+    fn ror(&mut self, operand: Operand) {
+        self.modify(operand, Self::rotate_right);
+    }
+
+    fn bit(&mut self, operand: Operand) {
+        let data = self.read_operand(operand);
+        self.set_flag(StatusFlags::ZERO, self.register_a & data == 0);
+        self.set_flag(StatusFlags::NEGATIVE, data & 0b1000_0000 != 0);
+        self.set_flag(StatusFlags::OVERFLOW, data & 0b0100_0000 != 0);
+    }
+
+    fn increment(&mut self, data: u8) -> u8 {
+        data.wrapping_add(1)
+    }
+
+    fn decrement(&mut self, data: u8) -> u8 {
+        data.wrapping_sub(1)
+    }
+
+    fn inc(&mut self, operand: Operand) {
+        self.modify(operand, Self::increment);
+    }
+
+    fn dec(&mut self, operand: Operand) {
+        self.modify(operand, Self::decrement);
+    }
+
+    fn inx(&mut self) {
+        self.register_x = self.register_x.wrapping_add(1);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn iny(&mut self) {
+        self.register_y = self.register_y.wrapping_add(1);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn dex(&mut self) {
+        self.register_x = self.register_x.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn dey(&mut self) {
+        self.register_y = self.register_y.wrapping_sub(1);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn lda(&mut self, operand: Operand) {
+        let value = self.read_operand(operand);
+        self.set_register_a(value);
+    }
+
+    fn ldx(&mut self, operand: Operand) {
+        self.register_x = self.read_operand(operand);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn ldy(&mut self, operand: Operand) {
+        self.register_y = self.read_operand(operand);
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn sta(&mut self, operand: Operand) {
+        self.write_operand(operand, self.register_a);
+    }
+
+    fn stx(&mut self, operand: Operand) {
+        self.write_operand(operand, self.register_x);
+    }
+
+    fn sty(&mut self, operand: Operand) {
+        self.write_operand(operand, self.register_y);
+    }
+
+    fn tax(&mut self) {
+        self.register_x = self.register_a;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn tay(&mut self) {
+        self.register_y = self.register_a;
+        self.update_zero_and_negative_flags(self.register_y);
+    }
+
+    fn txa(&mut self) {
+        self.set_register_a(self.register_x);
+    }
+
+    fn tya(&mut self) {
+        self.set_register_a(self.register_y);
+    }
+
+    fn jmp(&mut self, operand: Operand) {
+        self.program_counter = Self::operand_address(operand);
+    }
+
+    fn jsr(&mut self, operand: Operand) {
+        // The return address pushed is the last byte of the JSR instruction
+        self.stack_push_u16(self.program_counter.wrapping_sub(1));
+        self.program_counter = Self::operand_address(operand);
+    }
+
+    fn rts(&mut self) {
+        self.program_counter = self.stack_pop_u16().wrapping_add(1);
+    }
+
+    fn rti(&mut self) {
+        self.plp();
+        self.program_counter = self.stack_pop_u16();
+    }
+
+    fn brk(&mut self) {
+        // BRK is followed by a padding byte that the return address skips
+        self.program_counter = self.program_counter.wrapping_add(1);
+        self.interrupt(Interrupt::Brk);
+    }
+
+    fn pha(&mut self) {
+        self.stack_push(self.register_a);
+    }
+
+    fn pla(&mut self) {
+        let value = self.stack_pop();
+        self.set_register_a(value);
+    }
+
+    fn php(&mut self) {
+        self.stack_push((self.status | StatusFlags::BREAK | StatusFlags::UNUSED).bits());
+    }
+
+    fn plp(&mut self) {
+        let flags = StatusFlags::from_bits_truncate(self.stack_pop());
+        self.status = (flags - StatusFlags::BREAK) | StatusFlags::UNUSED;
+    }
+
+    fn tsx(&mut self) {
+        self.register_x = self.stack_pointer;
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn txs(&mut self) {
+        self.stack_pointer = self.register_x;
+    }
+
+    fn slo(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::shift_left);
+        self.set_register_a(self.register_a | result);
+    }
+
+    fn rla(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::rotate_left);
+        self.set_register_a(self.register_a & result);
+    }
+
+    fn sre(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::shift_right);
+        self.set_register_a(self.register_a ^ result);
+    }
+
+    fn rra(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::rotate_right);
+        self.add_to_register_a(result);
+    }
+
+    fn dcp(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::decrement);
+        self.compare_value(result, self.register_a);
+    }
+
+    fn isc(&mut self, operand: Operand) {
+        let result = self.modify(operand, Self::increment);
+        self.add_to_register_a(!result);
+    }
+
+    fn sax(&mut self, operand: Operand) {
+        self.write_operand(operand, self.register_a & self.register_x);
+    }
+
+    fn lax(&mut self, operand: Operand) {
+        self.lda(operand);
+        self.register_x = self.register_a;
+    }
+
+    fn anc(&mut self, operand: Operand) {
+        self.and(operand);
+        self.set_flag(
+            StatusFlags::CARRY,
+            self.status.contains(StatusFlags::NEGATIVE),
+        );
+    }
+
+    fn alr(&mut self, operand: Operand) {
+        self.and(operand);
+        self.lsr(Operand::Accumulator);
+    }
+
+    fn arr(&mut self, operand: Operand) {
+        self.and(operand);
+        self.ror(Operand::Accumulator);
+        // Carry and overflow come from bits 6 and 5 of the rotated result
+        let result = self.register_a;
+        let bit_6 = result & 0b0100_0000 != 0;
+        let bit_5 = result & 0b0010_0000 != 0;
+        self.set_flag(StatusFlags::CARRY, bit_6);
+        self.set_flag(StatusFlags::OVERFLOW, bit_6 ^ bit_5);
+    }
+
+    fn axs(&mut self, operand: Operand) {
+        let data = self.read_operand(operand);
+        let and = self.register_a & self.register_x;
+        self.set_flag(StatusFlags::CARRY, and >= data);
+        self.register_x = and.wrapping_sub(data);
+        self.update_zero_and_negative_flags(self.register_x);
+    }
+
+    fn las(&mut self, operand: Operand) {
+        let value = self.read_operand(operand) & self.stack_pointer;
+        self.register_a = value;
+        self.register_x = value;
+        self.stack_pointer = value;
+        self.update_zero_and_negative_flags(value);
+    }
+
+    // SHX, SHY, AHX and TAS store the value ANDed with the high byte of the
+    // base address plus one. When indexing crosses a page the corrupted value
+    // also replaces the high byte of the target address.
+    fn store_high_byte_and(&mut self, resolved: ResolvedOperand, index: u8, value: u8) {
+        let addr = Self::operand_address(resolved.operand);
+        let base_high = (addr.wrapping_sub(index as u16) >> 8) as u8;
+        let data = value & base_high.wrapping_add(1);
+        let target = if resolved.page_crossed {
+            ((data as u16) << 8) | (addr & 0x00FF)
+        } else {
+            addr
+        };
+        self.mem_write(target, data);
+    }
+
+    fn shx(&mut self, resolved: ResolvedOperand) {
+        self.store_high_byte_and(resolved, self.register_y, self.register_x);
+    }
+
+    fn shy(&mut self, resolved: ResolvedOperand) {
+        self.store_high_byte_and(resolved, self.register_x, self.register_y);
+    }
+
+    fn ahx(&mut self, resolved: ResolvedOperand) {
+        let value = self.register_a & self.register_x;
+        self.store_high_byte_and(resolved, self.register_y, value);
+    }
+
+    fn tas(&mut self, resolved: ResolvedOperand) {
+        self.stack_pointer = self.register_a & self.register_x;
+        self.store_high_byte_and(resolved, self.register_y, self.stack_pointer);
+    }
+
+    fn jam(&mut self, address: u16) {
+        warn!("CPU jammed at 0x{:04X}", address);
+        self.program_counter = address;
+        self.jammed = true;
+    }
+
+    // Applies the illegal opcode policy to a byte that isn't an instruction
+    fn illegal_opcode(&mut self, code: u8, address: u16) -> Result<Step, EmulatorError> {
+        match self.illegal_opcode_policy {
+            IllegalOpcodePolicy::Halt => {
+                self.program_counter = address;
+                return Err(EmulatorError::IllegalOpcode {
+                    opcode: code,
+                    program_counter: address,
+                });
+            }
+            IllegalOpcodePolicy::Nop => {
+                warn!(
+                    "Skipping illegal opcode 0x{:02X} at 0x{:04X}",
+                    code, address
+                );
+                self.cycles += 2;
+            }
+            IllegalOpcodePolicy::Jam => self.jam(address),
+        }
+
+        Ok(Step {
+            address,
+            opcode: code,
+            instruction: None,
+            cycles: if self.jammed { 0 } else { 2 },
+        })
+    }
+
+    fn lookup_opcode(&self, code: u8) -> Option<&'static OpCode> {
+        match OPCODES_MAP.get(&code) {
+            Some(opcode) => Some(opcode),
+            None if self.unofficial_opcodes => UNOFFICIAL_OPCODES_MAP.get(&code).copied(),
+            None => None,
+        }
+    }
+
+    /// Executes a single instruction, servicing any pending interrupt first
+    pub fn step(&mut self) -> Result<Step, EmulatorError> {
+        let mut step = self.execute()?;
+        // A DMA started by the instruction halts the CPU before the next one
+        let stall = self.bus.poll_dma(self.cycles % 2 == 1) as u64;
+        self.cycles += stall;
+        step.cycles += stall;
+        self.bus.tick(step.cycles as u16);
+        Ok(step)
+    }
+
+    fn execute(&mut self) -> Result<Step, EmulatorError> {
+        // A jammed CPU does nothing until it is reset
+        if self.jammed {
+            return Ok(Step {
+                address: self.program_counter,
+                opcode: self.mem_read(self.program_counter),
+                instruction: None,
+                cycles: 0,
+            });
+        }
+
+        let cycles_before = self.cycles;
+        self.poll_interrupts();
+
+        let address = self.program_counter;
+        let code = self.mem_read(address);
+        self.program_counter = self.program_counter.wrapping_add(1);
+
+        let opcode: &'static OpCode = match self.lookup_opcode(code) {
+            Some(opcode) => opcode,
+            None => {
+                let mut step = self.illegal_opcode(code, address)?;
+                step.cycles = self.cycles - cycles_before;
+                return Ok(step);
+            }
+        };
+        let resolved = self.resolve_operand(opcode.address_mode);
+        let operand = resolved.operand;
+
+        self.cycles += opcode.cycles as u64;
+        if resolved.page_crossed && opcode.adds_page_cross_cycle() {
+            self.cycles += 1;
+        }
+
+        // Step over the operand bytes, jumps and branches overwrite this
+        self.program_counter = self.program_counter.wrapping_add((opcode.len - 1) as u16);
+
+        match opcode.mnemonic.as_str() {
+            "ADC" => self.adc(operand),
+            "AND" => self.and(operand),
+            "ASL" => self.asl(operand),
+            "BCC" => self.branch(resolved, !self.status.contains(StatusFlags::CARRY)),
+            "BCS" => self.branch(resolved, self.status.contains(StatusFlags::CARRY)),
+            "BEQ" => self.branch(resolved, self.status.contains(StatusFlags::ZERO)),
+            "BIT" => self.bit(operand),
+            "BMI" => self.branch(resolved, self.status.contains(StatusFlags::NEGATIVE)),
+            "BNE" => self.branch(resolved, !self.status.contains(StatusFlags::ZERO)),
+            "BPL" => self.branch(resolved, !self.status.contains(StatusFlags::NEGATIVE)),
+            "BRK" => self.brk(),
+            "BVC" => self.branch(resolved, !self.status.contains(StatusFlags::OVERFLOW)),
+            "BVS" => self.branch(resolved, self.status.contains(StatusFlags::OVERFLOW)),
+            "CLC" => self.set_flag(StatusFlags::CARRY, false),
+            "CLD" => self.set_flag(StatusFlags::DECIMAL_MODE, false),
+            "CLI" => self.set_flag(StatusFlags::INTERRUPT_DISABLE, false),
+            "CLV" => self.set_flag(StatusFlags::OVERFLOW, false),
+            "CMP" => self.compare(operand, self.register_a),
+            "CPX" => self.compare(operand, self.register_x),
+            "CPY" => self.compare(operand, self.register_y),
+            "DEC" => self.dec(operand),
+            "DEX" => self.dex(),
+            "DEY" => self.dey(),
+            "EOR" => self.eor(operand),
+            "INC" => self.inc(operand),
+            "INX" => self.inx(),
+            "INY" => self.iny(),
+            "JMP" => self.jmp(operand),
+            "JSR" => self.jsr(operand),
+            "LDA" => self.lda(operand),
+            "LDX" => self.ldx(operand),
+            "LDY" => self.ldy(operand),
+            "LSR" => self.lsr(operand),
+            "NOP" => {}
+            "ORA" => self.ora(operand),
+            "PHA" => self.pha(),
+            "PHP" => self.php(),
+            "PLA" => self.pla(),
+            "PLP" => self.plp(),
+            "ROL" => self.rol(operand),
+            "ROR" => self.ror(operand),
+            "RTI" => self.rti(),
+            "RTS" => self.rts(),
+            "SBC" => self.sbc(operand),
+            "SEC" => self.set_flag(StatusFlags::CARRY, true),
+            "SED" => self.set_flag(StatusFlags::DECIMAL_MODE, true),
+            "SEI" => self.set_flag(StatusFlags::INTERRUPT_DISABLE, true),
+            "STA" => self.sta(operand),
+            "STX" => self.stx(operand),
+            "STY" => self.sty(operand),
+            "TAX" => self.tax(),
+            "TAY" => self.tay(),
+            "TSX" => self.tsx(),
+            "TXA" => self.txa(),
+            "TXS" => self.txs(),
+            "TYA" => self.tya(),
+            // Unofficial opcodes
+            "AHX" => self.ahx(resolved),
+            "ALR" => self.alr(operand),
+            "ANC" => self.anc(operand),
+            "ARR" => self.arr(operand),
+            "AXS" => self.axs(operand),
+            "DCP" => self.dcp(operand),
+            "ISC" => self.isc(operand),
+            "JAM" => self.jam(address),
+            "LAS" => self.las(operand),
+            "LAX" => self.lax(operand),
+            "RLA" => self.rla(operand),
+            "RRA" => self.rra(operand),
+            "SAX" => self.sax(operand),
+            "SHX" => self.shx(resolved),
+            "SHY" => self.shy(resolved),
+            "SLO" => self.slo(operand),
+            "SRE" => self.sre(operand),
+            "TAS" => self.tas(resolved),
+            _ => unreachable!(
+                "{} is in the opcode table but not dispatched",
+                opcode.mnemonic
+            ),
+        }
+
+        Ok(Step {
+            address,
+            opcode: code,
+            instruction: Some(opcode),
+            cycles: self.cycles - cycles_before,
+        })
+    }
+
+    /// Runs until the predicate holds after an instruction, or another stop
+    /// condition (BRK, a breakpoint or a jam) is reached first
+    pub fn run_until<F>(&mut self, predicate: F) -> Result<StopReason, EmulatorError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        self.is_running = true;
+        let result = self.run_loop(predicate);
+        self.is_running = false;
+        result
+    }
+
+    fn run_loop<F>(&mut self, mut predicate: F) -> Result<StopReason, EmulatorError>
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        let mut first = true;
+
+        loop {
+            // Resuming from a breakpoint must not stop on it again
+            if !first && self.breakpoints.contains(&self.program_counter) {
+                return Ok(StopReason::Breakpoint(self.program_counter));
+            }
+            first = false;
+
+            let step = self.step()?;
+            if self.jammed {
+                return Ok(StopReason::IllegalOpcode {
+                    opcode: step.opcode,
+                    address: step.address,
+                });
+            }
+            if step.opcode == 0x00 && self.halt_on_brk {
+                return Ok(StopReason::Brk);
+            }
+            if predicate(self) {
+                return Ok(StopReason::Predicate);
+            }
+        }
+    }
+
+    /// Runs until at least `cycles` more cycles have been executed
+    pub fn run_for_cycles(&mut self, cycles: u64) -> Result<StopReason, EmulatorError> {
+        let target = self.cycles + cycles;
+        match self.run_until(|cpu| cpu.cycles >= target)? {
+            StopReason::Predicate => Ok(StopReason::CycleBudget),
+            reason => Ok(reason),
+        }
+    }
+
+    pub fn run(&mut self) -> Result<StopReason, EmulatorError> {
+        info!("Starting to interpret bytes");
+        self.run_until(|_| false)
+    }
+
+    pub fn to_cpu_json(&self) -> String {
+        let mut monitored_memory: Vec<(String, Vec<u8>)> = Vec::new();
+        for i in 0..self.monitored_memory_range.1 {
+            let start_index = self.monitored_memory_range.0 + i * 64;
+            let end_index = self.monitored_memory_range.0 + (i + 1) * 64;
+            if start_index <= 0xFFFF {
+                let chunk = (start_index..end_index.min(0x10000))
+                    .map(|addr| self.bus.peek(addr as u16))
+                    .collect();
+                let address = format!("{:02X}", start_index);
+                monitored_memory.push(("0x".to_owned() + &address, chunk));
+            }
+        }
+
+        let cpu_state = json!({
+            "register_a": self.register_a,
+            "register_x": self.register_x,
+            "register_y": self.register_y,
+            "status": self.status,
+            "status_flags": self.status.flag_names(),
+            "program_counter": self.program_counter,
+            "stack_pointer": self.stack_pointer,
+            "cycles": self.cycles,
+            "memory": monitored_memory,
+            "is_running": self.is_running,
+            "debug_mode": self.debug_mode
+        });
+
+        cpu_state.to_string()
+    }
+
+    pub fn set_memory_start_address(&mut self, start: usize) {
+        self.monitored_memory_range = (start, 10);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IllegalOpcodePolicy, Operand, StatusFlags, StopReason, CPU};
+    use crate::instructions::AddressMode;
+    use crate::{
+        bus::{Bus, FlatRam, NesBus},
+        cartridge::Cartridge,
+        error::EmulatorError,
+        mapper::Nrom,
+    };
+
+    // Counts reads of one address, standing in for a register where reading
+    // has side effects
+    struct CountingBus {
+        ram: FlatRam,
+        watched: u16,
+        reads: usize,
+    }
+
+    impl Bus for CountingBus {
+        fn read(&mut self, addr: u16) -> u8 {
+            if addr == self.watched {
+                self.reads += 1;
+            }
+            self.ram.read(addr)
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            self.ram.write(addr, data);
+        }
+
+        fn peek(&self, addr: u16) -> u8 {
+            self.ram.peek(addr)
+        }
+    }
+
+    #[test]
+    fn test_0xa9_lda_immediate_load_data() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x05, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x05);
+        assert!(!cpu.status.contains(StatusFlags::ZERO));
+        assert!(!cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_0xa9_lda_zero_flag() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x00, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0);
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+        assert!(!cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_0xa9_lda_negative_flag() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x80, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(!cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_0xaa_tax_move_a_to_x() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x0A, 0xAA, 0x00]).unwrap();
+        assert_eq!(cpu.register_x, 10);
+    }
+
+    #[test]
+    fn test_5_ops_working_together() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0xC0, 0xAA, 0xE8, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 0xC1);
+    }
+
+    #[test]
+    fn test_inx_overflow() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA2, 0xFF, 0xE8, 0xE8, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 1);
+    }
+
+    #[test]
+    fn test_lda_from_memory() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x55);
+        cpu.load_and_run(vec![0xA5, 0x10, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x55);
+    }
+
+    #[test]
+    fn test_sta_zero_page_x_wraps() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x42, 0xA2, 0x02, 0x95, 0xFF, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x01), 0x42);
+    }
+
+    #[test]
+    fn test_adc_sets_carry_and_overflow() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x50, 0x69, 0x50, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0xA0);
+        assert!(cpu.status.contains(StatusFlags::OVERFLOW));
+        assert!(!cpu.status.contains(StatusFlags::CARRY));
+
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x69, 0x02, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_sbc_borrow() {
+        let mut cpu = CPU::new();
+        // SEC; LDA #$05; SBC #$06
+        cpu.load_and_run(vec![0x38, 0xA9, 0x05, 0xE9, 0x06, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0xFF);
+        assert!(!cpu.status.contains(StatusFlags::CARRY));
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_cmp_flags() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x10, 0xC9, 0x10, 0x00])
+            .unwrap();
+        assert!(cpu.status.contains(StatusFlags::ZERO | StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_bne_loop() {
+        let mut cpu = CPU::new();
+        // LDX #$05; loop: DEX; BNE loop; BRK
+        cpu.load_and_run(vec![0xA2, 0x05, 0xCA, 0xD0, 0xFD, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 0);
+        assert!(cpu.status.contains(StatusFlags::ZERO));
+    }
+
+    #[test]
+    fn test_jmp_absolute() {
+        let mut cpu = CPU::new();
+        // JMP $8005; LDA #$01; LDA #$02; BRK
+        cpu.load_and_run(vec![0x4C, 0x05, 0x80, 0xA9, 0x01, 0xA9, 0x02, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn test_rol_and_ror_through_carry() {
+        let mut cpu = CPU::new();
+        // LDA #$81; ROL A; ROR A
+        cpu.load_and_run(vec![0xA9, 0x81, 0x2A, 0x00]).unwrap();
+        assert_eq!(cpu.register_a, 0x02);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        cpu.load_and_run(vec![0x38, 0xA9, 0x01, 0x6A, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_indirect_y_load() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0x20, 0x0300);
+        cpu.mem_write(0x0305, 0x77);
+        cpu.load_and_run(vec![0xA0, 0x05, 0xB1, 0x20, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x77);
+    }
+
+    #[test]
+    fn test_resolve_absolute_x_page_cross() {
+        let mut cpu = CPU::new();
+        cpu.program_counter = 0x0200;
+        cpu.mem_write_u16(0x0200, 0x10F0);
+        cpu.register_x = 0x20;
+        let resolved = cpu.resolve_operand(AddressMode::AbsoluteX);
+        assert_eq!(resolved.operand, Operand::Address(0x1110));
+        assert!(resolved.page_crossed);
+
+        cpu.register_x = 0x0F;
+        let resolved = cpu.resolve_operand(AddressMode::AbsoluteX);
+        assert_eq!(resolved.operand, Operand::Address(0x10FF));
+        assert!(!resolved.page_crossed);
+    }
+
+    #[test]
+    fn test_resolve_indirect_x_wraps_in_zero_page() {
+        let mut cpu = CPU::new();
+        cpu.program_counter = 0x0200;
+        cpu.mem_write(0x0200, 0xFE);
+        cpu.register_x = 0x01;
+        cpu.mem_write(0x00FF, 0x34);
+        cpu.mem_write(0x0000, 0x12);
+        let resolved = cpu.resolve_operand(AddressMode::IndirectX);
+        assert_eq!(resolved.operand, Operand::Address(0x1234));
+    }
+
+    #[test]
+    fn test_resolve_indirect_y_page_cross() {
+        let mut cpu = CPU::new();
+        cpu.program_counter = 0x0200;
+        cpu.mem_write(0x0200, 0xFF);
+        cpu.mem_write(0x00FF, 0xFF);
+        cpu.mem_write(0x0000, 0x12);
+        cpu.register_y = 0x01;
+        let resolved = cpu.resolve_operand(AddressMode::IndirectY);
+        assert_eq!(resolved.operand, Operand::Address(0x1300));
+        assert!(resolved.page_crossed);
+    }
+
+    #[test]
+    fn test_resolve_relative_backwards() {
+        let mut cpu = CPU::new();
+        cpu.program_counter = 0x0201;
+        cpu.mem_write(0x0201, 0xFC);
+        let resolved = cpu.resolve_operand(AddressMode::Relative);
+        assert_eq!(resolved.operand, Operand::Address(0x01FE));
+        assert!(resolved.page_crossed);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_boundary_bug() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x30FF, 0x05);
+        cpu.mem_write(0x3000, 0x80);
+        cpu.mem_write(0x3100, 0x40);
+        // JMP ($30FF); LDA #$01; LDA #$02; BRK
+        cpu.load_and_run(vec![0x6C, 0xFF, 0x30, 0xA9, 0x01, 0xA9, 0x02, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn test_jsr_and_rts() {
+        let mut cpu = CPU::new();
+        // JSR $8006; LDX #$02; BRK; sub: LDA #$01; RTS
+        cpu.load_and_run(vec![0x20, 0x06, 0x80, 0xA2, 0x02, 0x00, 0xA9, 0x01, 0x60])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.register_x, 0x02);
+    }
+
+    #[test]
+    fn test_pha_pla_round_trip() {
+        let mut cpu = CPU::new();
+        // LDA #$80; PHA; LDA #$00; PLA
+        cpu.load_and_run(vec![0xA9, 0x80, 0x48, 0xA9, 0x00, 0x68, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_php_pushes_break_and_unused_bits() {
+        let mut cpu = CPU::new();
+        // SEC; PHP; PLA
+        cpu.load_and_run(vec![0x38, 0x08, 0x68, 0x00]).unwrap();
+        assert_eq!(cpu.register_a & 0b0011_0001, 0b0011_0001);
+    }
+
+    #[test]
+    fn test_brk_enters_irq_vector() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xFFFE, 0x9000);
+        cpu.load_and_run(vec![0x00]).unwrap();
+        assert_eq!(cpu.program_counter, 0x9000);
+        assert_eq!(cpu.stack_pointer, 0xFA);
+        // Return address skips the padding byte, pushed flags have B set
+        assert_eq!(cpu.mem_read(0x01FD), 0x80);
+        assert_eq!(cpu.mem_read(0x01FC), 0x02);
+        assert_eq!(cpu.mem_read(0x01FB) & 0b0011_0000, 0b0011_0000);
+        assert!(cpu.status.contains(StatusFlags::INTERRUPT_DISABLE));
+    }
+
+    #[test]
+    fn test_nmi_enters_vector_without_break_flag() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xFFFA, 0x9000);
+        cpu.mem_write(0x9000, 0x00);
+        cpu.load(vec![0xEA]);
+        cpu.reset();
+        cpu.trigger_nmi();
+        cpu.run().unwrap();
+        assert_eq!(cpu.mem_read(0x01FD), 0x80);
+        assert_eq!(cpu.mem_read(0x01FC), 0x00);
+        assert_eq!(cpu.mem_read(0x01FB) & 0b0011_0000, 0b0010_0000);
+    }
+
+    #[test]
+    fn test_irq_is_masked_by_interrupt_disable() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xFFFE, 0x9000);
+        cpu.mem_write(0x9000, 0xA2);
+        cpu.mem_write(0x9001, 0x07);
+        // LDA #$01; BRK with IRQ asserted while I is still set from reset
+        cpu.load(vec![0xA9, 0x01, 0x00]);
+        cpu.reset();
+        cpu.set_irq(true);
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.register_x, 0x00);
+    }
+
+    #[test]
+    fn test_rti_restores_status_and_program_counter() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xFFFA, 0x9000);
+        // Handler: LDX #$00 (sets Z); RTI
+        cpu.mem_write(0x9000, 0xA2);
+        cpu.mem_write(0x9001, 0x00);
+        cpu.mem_write(0x9002, 0x40);
+        // SEC; BRK, the NMI fires before SEC runs
+        cpu.load(vec![0x38, 0x00]);
+        cpu.reset();
+        cpu.trigger_nmi();
+        cpu.run().unwrap();
+        assert!(!cpu.status.contains(StatusFlags::ZERO));
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+        assert_eq!(cpu.mem_read(0x01FC), 0x03);
+        assert_eq!(cpu.stack_pointer, 0xFA);
+    }
+
+    #[test]
+    fn test_status_flags_serialize_readably() {
+        let mut cpu = CPU::new();
+        cpu.load_and_run(vec![0xA9, 0x00, 0x38, 0x00]).unwrap();
+        assert_eq!(cpu.status.to_string(), "nvUbdIZC");
+
+        let json: serde_json::Value = serde_json::from_str(&cpu.to_cpu_json()).unwrap();
+        assert_eq!(json["status"], "nvUbdIZC");
+        assert_eq!(
+            json["status_flags"],
+            serde_json::json!(["U", "I", "Z", "C"])
+        );
+    }
+
+    #[test]
+    fn test_cycles_for_straight_line_code() {
+        let mut cpu = CPU::new();
+        // LDA #$01 (2); STA $0200 (4); TAX (2); BRK (7) after 7 for reset
+        cpu.load_and_run(vec![0xA9, 0x01, 0x8D, 0x00, 0x02, 0xAA, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 4 + 2 + 7);
+    }
+
+    #[test]
+    fn test_cycles_page_cross_penalty_only_on_reads() {
+        let mut cpu = CPU::new();
+        // LDX #$FF (2); LDA $10FF,X (4+1); STA $10FF,X (5)
+        cpu.load_and_run(vec![0xA2, 0xFF, 0xBD, 0xFF, 0x10, 0x9D, 0xFF, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 5 + 5 + 7);
+
+        // LDX #$00 (2); LDA $10FF,X (4)
+        cpu.load_and_run(vec![0xA2, 0x00, 0xBD, 0xFF, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 4 + 7);
+    }
+
+    #[test]
+    fn test_cycles_branch_penalties() {
+        let mut cpu = CPU::new();
+        // SEC (2); BCC +0 not taken (2); BCS +0 taken (3)
+        cpu.load_and_run(vec![0x38, 0x90, 0x00, 0xB0, 0x00, 0x00])
+            .unwrap();
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 3 + 7);
+
+        // SEC at $80FB, then BCS from $80FC across to $8100 costs 4
+        let mut program = vec![0xEA; 0x106];
+        program[0] = 0x4C;
+        program[1] = 0xFB;
+        program[2] = 0x80;
+        program[0xFB] = 0x38;
+        program[0xFC] = 0xB0;
+        program[0xFD] = 0x02;
+        program[0x100] = 0x00;
+        cpu.load_and_run(program).unwrap();
+        assert_eq!(cpu.cycles, 7 + 3 + 2 + 4 + 7);
+    }
+
+    #[test]
+    fn test_cycles_for_nmi_entry() {
+        let mut cpu = CPU::new();
+        cpu.mem_write_u16(0xFFFA, 0x9000);
+        cpu.load(vec![0xEA]);
+        cpu.reset();
+        cpu.trigger_nmi();
+        cpu.run().unwrap();
+        assert_eq!(cpu.cycles, 7 + 7 + 7);
+    }
+
+    #[test]
+    fn test_step_reports_instruction_and_cycles() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA9, 0x01, 0x8D, 0x00, 0x02, 0x00]);
+        cpu.reset();
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.address, 0x8000);
+        assert_eq!(step.instruction.unwrap().mnemonic, "LDA");
+        assert_eq!(step.cycles, 2);
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.address, 0x8002);
+        assert_eq!(step.instruction.unwrap().mnemonic, "STA");
+        assert_eq!(step.cycles, 4);
+        assert_eq!(cpu.mem_read(0x0200), 0x01);
+    }
+
+    #[test]
+    fn test_run_stops_on_brk() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load_and_run(vec![0xEA, 0x00]).unwrap(), StopReason::Brk);
+        assert!(!cpu.is_running);
+    }
+
+    #[test]
+    fn test_run_for_cycles_stops_on_budget() {
+        let mut cpu = CPU::new();
+        // loop: JMP loop
+        cpu.load(vec![0x4C, 0x00, 0x80]);
+        cpu.reset();
+        assert_eq!(cpu.run_for_cycles(10).unwrap(), StopReason::CycleBudget);
+        assert_eq!(cpu.cycles, 7 + 12);
+        assert_eq!(cpu.run_for_cycles(1).unwrap(), StopReason::CycleBudget);
+        assert_eq!(cpu.cycles, 7 + 15);
+    }
+
+    #[test]
+    fn test_run_stops_and_resumes_at_breakpoint() {
+        let mut cpu = CPU::new();
+        cpu.load(vec![0xA9, 0x01, 0xA9, 0x02, 0x00]);
+        cpu.reset();
+        cpu.breakpoints.insert(0x8002);
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Breakpoint(0x8002));
+        assert_eq!(cpu.register_a, 0x01);
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+        assert_eq!(cpu.register_a, 0x02);
+    }
+
+    #[test]
+    fn test_run_until_predicate() {
+        let mut cpu = CPU::new();
+        // loop: INX; JMP loop
+        cpu.load(vec![0xE8, 0x4C, 0x00, 0x80]);
+        cpu.reset();
+        let reason = cpu.run_until(|cpu| cpu.register_x == 3).unwrap();
+        assert_eq!(reason, StopReason::Predicate);
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+
+    #[test]
+    fn test_illegal_opcode_halts_with_error() {
+        let mut cpu = CPU::new();
+        cpu.unofficial_opcodes = false;
+        match cpu.load_and_run(vec![0xEA, 0x02]) {
+            Err(EmulatorError::IllegalOpcode {
+                opcode,
+                program_counter,
+            }) => {
+                assert_eq!(opcode, 0x02);
+                assert_eq!(program_counter, 0x8001);
+            }
+            other => panic!("Expected an illegal opcode error, got {:?}", other),
+        }
+        assert_eq!(cpu.program_counter, 0x8001);
+        assert!(!cpu.is_running);
+    }
+
+    #[test]
+    fn test_illegal_opcode_as_nop() {
+        let mut cpu = CPU::new();
+        cpu.unofficial_opcodes = false;
+        cpu.illegal_opcode_policy = IllegalOpcodePolicy::Nop;
+        cpu.load(vec![0x02, 0xA9, 0x01, 0x00]);
+        cpu.reset();
+
+        let step = cpu.step().unwrap();
+        assert_eq!(step.opcode, 0x02);
+        assert!(step.instruction.is_none());
+        assert_eq!(step.cycles, 2);
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+        assert_eq!(cpu.register_a, 0x01);
+    }
+
+    #[test]
+    fn test_illegal_opcode_jams_until_reset() {
+        let mut cpu = CPU::new();
+        cpu.unofficial_opcodes = false;
+        cpu.illegal_opcode_policy = IllegalOpcodePolicy::Jam;
+        cpu.load(vec![0xEA, 0x02]);
+        cpu.reset();
+
+        let jammed = StopReason::IllegalOpcode {
+            opcode: 0x02,
+            address: 0x8001,
+        };
+        assert_eq!(cpu.run().unwrap(), jammed);
+        assert_eq!(cpu.run_for_cycles(100).unwrap(), jammed);
+        assert_eq!(cpu.program_counter, 0x8001);
+
+        cpu.reset();
+        assert!(!cpu.jammed);
+        assert_eq!(cpu.program_counter, 0x8000);
+    }
+
+    #[test]
+    fn test_lax_and_sax() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x8F);
+        // LAX $10; LDA #$F0; SAX $11
+        cpu.load_and_run(vec![0xA7, 0x10, 0xA9, 0xF0, 0x87, 0x11, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 0x8F);
+        assert_eq!(cpu.mem_read(0x11), 0x80);
+    }
+
+    #[test]
+    fn test_dcp_and_isc() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x06);
+        cpu.mem_write(0x11, 0x01);
+        // LDA #$05; DCP $10; SEC; ISC $11
+        cpu.load_and_run(vec![0xA9, 0x05, 0xC7, 0x10, 0x38, 0xE7, 0x11, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x05);
+        assert_eq!(cpu.mem_read(0x11), 0x02);
+        assert_eq!(cpu.register_a, 0x03);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_slo_rla_sre_rra() {
+        let mut cpu = CPU::new();
+        cpu.mem_write(0x10, 0x81);
+        // LDA #$01; SLO $10 -> mem $02, A $03, C set
+        cpu.load_and_run(vec![0xA9, 0x01, 0x07, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x02);
+        assert_eq!(cpu.register_a, 0x03);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        cpu.mem_write(0x10, 0x03);
+        // LDA #$FF; SRE $10 -> mem $01, A $FE, C set
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x47, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.register_a, 0xFE);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        cpu.mem_write(0x10, 0x80);
+        // SEC; LDA #$0F; RLA $10 -> mem $01, A $01
+        cpu.load_and_run(vec![0x38, 0xA9, 0x0F, 0x27, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.register_a, 0x01);
+
+        cpu.mem_write(0x10, 0x02);
+        // LDA #$10; RRA $10 -> mem $01, A $11
+        cpu.load_and_run(vec![0xA9, 0x10, 0x67, 0x10, 0x00])
+            .unwrap();
+        assert_eq!(cpu.mem_read(0x10), 0x01);
+        assert_eq!(cpu.register_a, 0x11);
+    }
+
+    #[test]
+    fn test_unofficial_rmw_opcodes_read_operand_once() {
+        // SLO, RLA, SRE, RRA, DCP and ISC on $10
+        for opcode in [0x07, 0x27, 0x47, 0x67, 0xC7, 0xE7] {
+            let mut ram = FlatRam::new();
+            ram.load(0x8000, &[opcode, 0x10]);
+            let mut cpu = CPU::with_bus(CountingBus {
+                ram,
+                watched: 0x0010,
+                reads: 0,
+            });
+            cpu.program_counter = 0x8000;
+            cpu.step().unwrap();
+            assert_eq!(cpu.bus.reads, 1, "opcode {:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_immediate_unofficial_opcodes() {
+        let mut cpu = CPU::new();
+        // LDA #$FF; ANC #$80
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x0B, 0x80, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x80);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        // LDA #$FF; ALR #$03
+        cpu.load_and_run(vec![0xA9, 0xFF, 0x4B, 0x03, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0x01);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+
+        // SEC; LDA #$FF; ARR #$C0 -> A $E0, C set, V clear
+        cpu.load_and_run(vec![0x38, 0xA9, 0xFF, 0x6B, 0xC0, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_a, 0xE0);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+        assert!(!cpu.status.contains(StatusFlags::OVERFLOW));
+
+        // LDA #$0F; LDX #$FC; AXS #$02
+        cpu.load_and_run(vec![0xA9, 0x0F, 0xA2, 0xFC, 0xCB, 0x02, 0x00])
+            .unwrap();
+        assert_eq!(cpu.register_x, 0x0A);
+        assert!(cpu.status.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_multi_byte_nops_and_timing() {
+        let mut cpu = CPU::new();
+        // NOP #$FF (2); NOP $10 (3); NOP $10FF,X with X=1 (4+1); NOP (2)
+        cpu.load_and_run(vec![
+            0xA2, 0x01, 0x80, 0xFF, 0x04, 0x10, 0x1C, 0xFF, 0x10, 0x1A, 0x00,
+        ])
+        .unwrap();
+        assert_eq!(cpu.program_counter, 0x0000);
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 3 + 5 + 2 + 7);
+    }
+
+    #[test]
+    fn test_jam_opcode_locks_up() {
+        let mut cpu = CPU::new();
+        let reason = cpu.load_and_run(vec![0xEA, 0x12, 0xA9, 0x01]).unwrap();
+        assert_eq!(
+            reason,
+            StopReason::IllegalOpcode {
+                opcode: 0x12,
+                address: 0x8001
+            }
+        );
+        assert_eq!(cpu.register_a, 0x00);
+    }
+
+    #[test]
+    fn test_strict_mode_rejects_unofficial_opcodes() {
+        let mut cpu = CPU::new();
+        cpu.unofficial_opcodes = false;
+        assert!(matches!(
+            cpu.load_and_run(vec![0xA7, 0x10, 0x00]),
+            Err(EmulatorError::IllegalOpcode { opcode: 0xA7, .. })
+        ));
+    }
+
+    #[test]
+    fn test_runs_from_nes_bus() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // LDA #$07; STA $0805 (mirror of $0005); BRK
+        prg_rom[..6].copy_from_slice(&[0xA9, 0x07, 0x8D, 0x05, 0x08, 0x00]);
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0xC0;
+
+        let mut cpu = CPU::with_bus(NesBus::new(Box::new(Nrom::new(prg_rom))));
+        cpu.reset();
+        assert_eq!(cpu.program_counter, 0xC000);
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+        assert_eq!(cpu.mem_read(0x0005), 0x07);
+    }
+
+    #[test]
+    fn test_open_bus_read_sees_operand_high_byte() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // LDA $5000; BRK
+        prg_rom[..4].copy_from_slice(&[0xAD, 0x00, 0x50, 0x00]);
+        prg_rom[0x3FFD] = 0xC0;
+
+        let mut cpu = CPU::with_bus(NesBus::new(Box::new(Nrom::new(prg_rom))));
+        cpu.reset();
+        cpu.run().unwrap();
+        assert_eq!(cpu.register_a, 0x50);
+    }
+
+    #[test]
+    fn test_boots_ines_rom() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // LDX #$03; STX $6000; BRK
+        prg_rom[..6].copy_from_slice(&[0xA2, 0x03, 0x8E, 0x00, 0x60, 0x00]);
+        prg_rom[0x3FFD] = 0x80;
+        let raw = Cartridge::new(0, prg_rom, vec![]).to_ines();
+
+        let mut cpu = CPU::from_cartridge(Cartridge::from_bytes(&raw).unwrap()).unwrap();
+        assert_eq!(cpu.program_counter, 0x8000);
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+        assert_eq!(cpu.mem_read(0x6000), 0x03);
+    }
+
+    #[test]
+    fn test_mapper_irq_interrupts_cpu() {
+        let mut prg_rom = vec![0xEA; 0x10000];
+        // CLI at $E000 followed by NOPs, the IRQ handler at $E100 is a BRK
+        prg_rom[0xE000] = 0x58;
+        prg_rom[0xE100] = 0x00;
+        prg_rom[0xFFFC..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE1]);
+        let mut cpu = CPU::from_cartridge(Cartridge::new(4, prg_rom, vec![])).unwrap();
+
+        // Latch 0, enabled, then a single A12 rise after a long low period
+        cpu.mem_write(0xC000, 0);
+        cpu.mem_write(0xE001, 0);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.bus.mapper_mut().ppu_address(0x1000);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.program_counter, 0xE100);
+        assert!(cpu.status.contains(StatusFlags::INTERRUPT_DISABLE));
+    }
+
+    #[test]
+    fn test_run_frames() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // loop: JMP loop
+        prg_rom[..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        prg_rom[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::from_cartridge(Cartridge::new(0, prg_rom, vec![])).unwrap();
+
+        assert_eq!(cpu.run_frames(2).unwrap(), StopReason::FrameBudget);
+        assert_eq!(cpu.bus.ppu().frame, 2);
+        // Two frames of 341 x 262 dots at three dots per CPU cycle, on top of
+        // the reset sequence
+        assert!((cpu.cycles - 7).abs_diff(2 * 341 * 262 / 3) <= 3);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // LDA $00; STA $4014; LDA #$02; STA $4014
+        prg_rom[..10]
+            .copy_from_slice(&[0xA5, 0x00, 0x8D, 0x14, 0x40, 0xA9, 0x02, 0x8D, 0x14, 0x40]);
+        prg_rom[0x3FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::from_cartridge(Cartridge::new(0, prg_rom, vec![])).unwrap();
+        cpu.mem_write(0x0000, 0x42);
+
+        // The write lands on cycle 7 + 3 + 4, an even cycle
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 513);
+        assert_eq!(cpu.bus.ppu().oam[0], 0x42);
+
+        // Then on an odd one, which costs an extra alignment cycle
+        cpu.step().unwrap();
+        assert_eq!(cpu.step().unwrap().cycles, 4 + 514);
+        assert_eq!(cpu.cycles, 7 + 3 + 4 + 513 + 2 + 4 + 514);
+    }
+
+    #[test]
+    fn test_ppu_vblank_raises_nmi() {
+        let mut prg_rom = vec![0x00; 0x4000];
+        // LDA #$80; STA $2000; loop: JMP loop
+        prg_rom[..8].copy_from_slice(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        // The NMI handler at $8100 is a BRK
+        prg_rom[0x3FFA..].copy_from_slice(&[0x00, 0x81, 0x00, 0x80, 0x00, 0x00]);
+        let mut cpu = CPU::from_cartridge(Cartridge::new(0, prg_rom, vec![])).unwrap();
+
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+        assert_eq!(cpu.bus.ppu().scanline, 241);
+        // Three bytes were pushed by the NMI and three more by the BRK
+        assert_eq!(cpu.stack_pointer, 0xFD - 6);
     }
 }
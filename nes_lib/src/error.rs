@@ -3,5 +3,26 @@ use thiserror::Error;
 #[derive(Error, Debug)]
 pub enum EmulatorError {
     #[error("I/O Error: {0}")]
-    IOError(#[from] std::io::Error)
-}
\ No newline at end of file
+    IOError(#[from] std::io::Error),
+
+    #[error("Illegal opcode 0x{opcode:02X} at 0x{program_counter:04X}")]
+    IllegalOpcode { opcode: u8, program_counter: u16 },
+
+    #[error("Not an iNES file, the header is missing the NES<EOF> tag")]
+    InvalidNesSignature,
+
+    #[error("ROM is truncated: expected {expected} bytes but found {actual}")]
+    TruncatedRom { expected: usize, actual: usize },
+
+    #[error("Invalid ROM header: {0}")]
+    InvalidRomHeader(String),
+
+    #[error("Mapper {0} is not supported")]
+    UnsupportedMapper(u16),
+
+    #[error("Palette files hold 192 or 1536 bytes, found {0}")]
+    InvalidPalette(usize),
+
+    #[error("Sample rate {0}Hz is out of range")]
+    InvalidSampleRate(u32),
+}
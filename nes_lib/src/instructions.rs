@@ -40,6 +40,7 @@ impl fmt::Display for AddressMode {
     }
 }
 
+#[derive(Debug)]
 pub struct OpCode {
     pub opcode: u8,
     pub mnemonic: String,
@@ -58,6 +59,19 @@ impl OpCode {
             address_mode
         }
     }
+
+    /// Whether an indexed access that crosses a page costs one extra cycle.
+    /// Only instructions that just read their operand can skip the fix-up
+    /// cycle, stores and read-modify-write instructions always take it.
+    pub fn adds_page_cross_cycle(&self) -> bool {
+        matches!(
+            self.address_mode,
+            AddressMode::AbsoluteX | AddressMode::AbsoluteY | AddressMode::IndirectY
+        ) && matches!(
+            self.mnemonic.as_str(),
+            "ADC" | "AND" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "ORA" | "SBC" | "LAX" | "LAS" | "NOP"
+        )
+    }
 }
 
 lazy_static! {
@@ -208,6 +222,7 @@ lazy_static! {
         OpCode::new(0xA6, String::from("LDX"), 2, 3, AddressMode::ZeroPage),
         OpCode::new(0xB6, String::from("LDX"), 2, 4, AddressMode::ZeroPageY),
         OpCode::new(0xAE, String::from("LDX"), 3, 4, AddressMode::Absolute),
+        OpCode::new(0xBE, String::from("LDX"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteY),
 
         // LDY
         OpCode::new(0xA0, String::from("LDY"), 2, 2, AddressMode::Immediate),
@@ -294,6 +309,7 @@ lazy_static! {
         OpCode::new(0x9D, String::from("STA"), 3, 5, AddressMode::AbsoluteX),
         OpCode::new(0x99, String::from("STA"), 3, 5, AddressMode::AbsoluteY),
         OpCode::new(0x81, String::from("STA"), 2, 6, AddressMode::IndirectX),
+        OpCode::new(0x91, String::from("STA"), 2, 6, AddressMode::IndirectY),
 
         // STX
         OpCode::new(0x86, String::from("STX"), 2, 3, AddressMode::ZeroPage),
@@ -325,10 +341,166 @@ lazy_static! {
 
     ];
 
+    // The undocumented opcodes that behave the same on every 6502. XAA ($8B)
+    // and LXA ($AB) depend on the chip and temperature so are left out.
+    pub static ref UNOFFICIAL_OPCODES: Vec<OpCode> = vec![
+        // NOP
+        OpCode::new(0x1A, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0x3A, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0x5A, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0x7A, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0xDA, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0xFA, String::from("NOP"), 1, 2, AddressMode::Implied),
+        OpCode::new(0x80, String::from("NOP"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0x82, String::from("NOP"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0x89, String::from("NOP"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0xC2, String::from("NOP"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0xE2, String::from("NOP"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0x04, String::from("NOP"), 2, 3, AddressMode::ZeroPage),
+        OpCode::new(0x44, String::from("NOP"), 2, 3, AddressMode::ZeroPage),
+        OpCode::new(0x64, String::from("NOP"), 2, 3, AddressMode::ZeroPage),
+        OpCode::new(0x14, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0x34, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0x54, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0x74, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0xD4, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0xF4, String::from("NOP"), 2, 4, AddressMode::ZeroPageX),
+        OpCode::new(0x0C, String::from("NOP"), 3, 4, AddressMode::Absolute),
+        OpCode::new(0x1C, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+        OpCode::new(0x3C, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+        OpCode::new(0x5C, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+        OpCode::new(0x7C, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+        OpCode::new(0xDC, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+        OpCode::new(0xFC, String::from("NOP"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteX),
+
+        // SLO
+        OpCode::new(0x07, String::from("SLO"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0x17, String::from("SLO"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0x0F, String::from("SLO"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0x1F, String::from("SLO"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0x1B, String::from("SLO"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0x03, String::from("SLO"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0x13, String::from("SLO"), 2, 8, AddressMode::IndirectY),
+
+        // RLA
+        OpCode::new(0x27, String::from("RLA"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0x37, String::from("RLA"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0x2F, String::from("RLA"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0x3F, String::from("RLA"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0x3B, String::from("RLA"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0x23, String::from("RLA"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0x33, String::from("RLA"), 2, 8, AddressMode::IndirectY),
+
+        // SRE
+        OpCode::new(0x47, String::from("SRE"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0x57, String::from("SRE"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0x4F, String::from("SRE"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0x5F, String::from("SRE"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0x5B, String::from("SRE"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0x43, String::from("SRE"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0x53, String::from("SRE"), 2, 8, AddressMode::IndirectY),
+
+        // RRA
+        OpCode::new(0x67, String::from("RRA"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0x77, String::from("RRA"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0x6F, String::from("RRA"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0x7F, String::from("RRA"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0x7B, String::from("RRA"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0x63, String::from("RRA"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0x73, String::from("RRA"), 2, 8, AddressMode::IndirectY),
+
+        // SAX
+        OpCode::new(0x87, String::from("SAX"), 2, 3, AddressMode::ZeroPage),
+        OpCode::new(0x97, String::from("SAX"), 2, 4, AddressMode::ZeroPageY),
+        OpCode::new(0x8F, String::from("SAX"), 3, 4, AddressMode::Absolute),
+        OpCode::new(0x83, String::from("SAX"), 2, 6, AddressMode::IndirectX),
+
+        // LAX
+        OpCode::new(0xA7, String::from("LAX"), 2, 3, AddressMode::ZeroPage),
+        OpCode::new(0xB7, String::from("LAX"), 2, 4, AddressMode::ZeroPageY),
+        OpCode::new(0xAF, String::from("LAX"), 3, 4, AddressMode::Absolute),
+        OpCode::new(0xBF, String::from("LAX"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteY),
+        OpCode::new(0xA3, String::from("LAX"), 2, 6, AddressMode::IndirectX),
+        OpCode::new(0xB3, String::from("LAX"), 2, 5 /* +1 if page crossed */, AddressMode::IndirectY),
+
+        // DCP
+        OpCode::new(0xC7, String::from("DCP"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0xD7, String::from("DCP"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0xCF, String::from("DCP"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0xDF, String::from("DCP"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0xDB, String::from("DCP"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0xC3, String::from("DCP"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0xD3, String::from("DCP"), 2, 8, AddressMode::IndirectY),
+
+        // ISC
+        OpCode::new(0xE7, String::from("ISC"), 2, 5, AddressMode::ZeroPage),
+        OpCode::new(0xF7, String::from("ISC"), 2, 6, AddressMode::ZeroPageX),
+        OpCode::new(0xEF, String::from("ISC"), 3, 6, AddressMode::Absolute),
+        OpCode::new(0xFF, String::from("ISC"), 3, 7, AddressMode::AbsoluteX),
+        OpCode::new(0xFB, String::from("ISC"), 3, 7, AddressMode::AbsoluteY),
+        OpCode::new(0xE3, String::from("ISC"), 2, 8, AddressMode::IndirectX),
+        OpCode::new(0xF3, String::from("ISC"), 2, 8, AddressMode::IndirectY),
+
+        // ANC
+        OpCode::new(0x0B, String::from("ANC"), 2, 2, AddressMode::Immediate),
+        OpCode::new(0x2B, String::from("ANC"), 2, 2, AddressMode::Immediate),
+
+        // ALR
+        OpCode::new(0x4B, String::from("ALR"), 2, 2, AddressMode::Immediate),
+
+        // ARR
+        OpCode::new(0x6B, String::from("ARR"), 2, 2, AddressMode::Immediate),
+
+        // AXS
+        OpCode::new(0xCB, String::from("AXS"), 2, 2, AddressMode::Immediate),
+
+        // SBC
+        OpCode::new(0xEB, String::from("SBC"), 2, 2, AddressMode::Immediate),
+
+        // LAS
+        OpCode::new(0xBB, String::from("LAS"), 3, 4 /* +1 if page crossed */, AddressMode::AbsoluteY),
+
+        // AHX
+        OpCode::new(0x9F, String::from("AHX"), 3, 5, AddressMode::AbsoluteY),
+        OpCode::new(0x93, String::from("AHX"), 2, 6, AddressMode::IndirectY),
+
+        // SHY
+        OpCode::new(0x9C, String::from("SHY"), 3, 5, AddressMode::AbsoluteX),
+
+        // SHX
+        OpCode::new(0x9E, String::from("SHX"), 3, 5, AddressMode::AbsoluteY),
+
+        // TAS
+        OpCode::new(0x9B, String::from("TAS"), 3, 5, AddressMode::AbsoluteY),
+
+        // JAM
+        OpCode::new(0x02, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x12, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x22, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x32, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x42, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x52, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x62, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x72, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0x92, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0xB2, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0xD2, String::from("JAM"), 1, 0, AddressMode::Implied),
+        OpCode::new(0xF2, String::from("JAM"), 1, 0, AddressMode::Implied),
+
+    ];
+
     pub static ref OPCODES_MAP: HashMap<u8, &'static OpCode> = {
         let mut map = HashMap::new();
         for opcode in CPU_OPCODES.iter() {
-            map.insert(opcode.opcode, opcode.clone());
+            map.insert(opcode.opcode, opcode);
+        }
+        map
+    };
+
+    pub static ref UNOFFICIAL_OPCODES_MAP: HashMap<u8, &'static OpCode> = {
+        let mut map = HashMap::new();
+        for opcode in UNOFFICIAL_OPCODES.iter() {
+            map.insert(opcode.opcode, opcode);
         }
         map
     };
@@ -2,9 +2,15 @@ use std::path::PathBuf;
 
 use error::EmulatorError;
 
+pub mod apu;
+pub mod bus;
+pub mod cartridge;
+pub mod controller;
 pub mod cpu;
 pub mod error;
 pub mod instructions;
+pub mod mapper;
+pub mod ppu;
 
 pub fn open_bin_file(file: &PathBuf) -> Result<Vec<u8>, EmulatorError> {
     let bytes = std::fs::read(file)?;
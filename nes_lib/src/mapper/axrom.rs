@@ -0,0 +1,100 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const PRG_BANK_SIZE: usize = 0x8000;
+
+/// Mapper 7. A latch selects a 32KiB PRG bank and which 1KiB of VRAM all
+/// four nametables point at.
+#[derive(Debug)]
+pub struct Axrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    latch: u8,
+    /// Only AMROM boards have bus conflicts, ANROM and AOROM disable the ROM
+    /// during writes
+    pub bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        Axrom {
+            bus_conflicts: super::has_bus_conflicts(&cartridge, false),
+            chr: ChrMemory::from_cartridge(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            latch: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => {
+                let bank = (self.latch & 0b0111) as usize;
+                let offset = bank * PRG_BANK_SIZE + (addr - PRG_ROM) as usize;
+                Some(self.prg_rom[offset % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.latch = if self.bus_conflicts {
+                data & self.cpu_read(addr).unwrap_or(0xFF)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.latch & 0b1_0000 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Axrom, Mapper};
+    use crate::cartridge::{Cartridge, Mirroring, RomFormat};
+
+    #[test]
+    fn test_axrom_switches_prg_and_mirroring() {
+        let prg_rom = (0..0x40000).map(|i| (i / 0x8000) as u8).collect();
+        let mut axrom = Axrom::from_cartridge(Cartridge::new(7, prg_rom, vec![]));
+        assert!(!axrom.bus_conflicts);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+
+        axrom.cpu_write(0x8000, 0b1_0110);
+        assert_eq!(axrom.cpu_read(0x8000), Some(6));
+        assert_eq!(axrom.cpu_read(0xFFFF), Some(6));
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_amrom_submapper_has_bus_conflicts() {
+        let mut cartridge = Cartridge::new(7, vec![0x01; 0x20000], vec![]);
+        cartridge.format = RomFormat::Nes2;
+        cartridge.submapper = 2;
+        let mut axrom = Axrom::from_cartridge(cartridge);
+        assert!(axrom.bus_conflicts);
+
+        axrom.cpu_write(0x8000, 0b1_0011);
+        assert_eq!(axrom.mirroring(), Mirroring::SingleScreenLower);
+    }
+}
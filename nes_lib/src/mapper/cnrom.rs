@@ -0,0 +1,101 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const CHR_BANK_SIZE: usize = 0x2000;
+
+/// Mapper 3. NROM style PRG ROM with a latch selecting the 8KiB CHR bank.
+#[derive(Debug)]
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    chr_bank: u8,
+    /// The ROM drives the data bus during latch writes, so the latched value
+    /// is ANDed with the byte stored at the written address
+    pub bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        Cnrom {
+            bus_conflicts: super::has_bus_conflicts(&cartridge, true),
+            chr: ChrMemory::from_cartridge(&cartridge),
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        self.chr_bank as usize * CHR_BANK_SIZE + (addr as usize & 0x1FFF)
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_ROM..=PRG_ROM_END => {
+                Some(self.prg_rom[(addr - PRG_ROM) as usize % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.chr_bank = if self.bus_conflicts {
+                data & self.cpu_read(addr).unwrap_or(0xFF)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr.write(offset, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cnrom, Mapper};
+    use crate::cartridge::Cartridge;
+
+    #[test]
+    fn test_cnrom_switches_chr_bank() {
+        let chr_rom = (0..0x8000).map(|i| (i / 0x2000) as u8).collect();
+        let mut cnrom = Cnrom::from_cartridge(Cartridge::new(3, vec![0xFF; 0x4000], chr_rom));
+
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+        cnrom.cpu_write(0x8000, 2);
+        assert_eq!(cnrom.ppu_read(0x1FFF), 2);
+        assert_eq!(cnrom.cpu_read(0xC000), Some(0xFF));
+    }
+
+    #[test]
+    fn test_cnrom_bus_conflicts() {
+        let chr_rom = (0..0x8000).map(|i| (i / 0x2000) as u8).collect();
+        let mut prg_rom = vec![0xFF; 0x8000];
+        prg_rom[0x0010] = 0x01;
+        let mut cnrom = Cnrom::from_cartridge(Cartridge::new(3, prg_rom, chr_rom));
+
+        cnrom.cpu_write(0x8010, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 1);
+
+        cnrom.bus_conflicts = false;
+        cnrom.cpu_write(0x8010, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 3);
+    }
+}
@@ -0,0 +1,388 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+const PRG_BANK_SIZE: usize = 0x4000;
+const CHR_BANK_SIZE: usize = 0x1000;
+const PRG_RAM_BANK_SIZE: usize = 0x2000;
+
+// The shift register is full once this marker bit reaches bit 0
+const SHIFT_RESET: u8 = 0b1_0000;
+
+/// Which MMC1 board the cartridge uses. The boards wire the unused high CHR
+/// bank lines to PRG ROM and PRG RAM instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Board {
+    /// SKROM, SLROM and friends, plus the SUROM/SOROM/SXROM bank lines which
+    /// only matter when the ROM or RAM is big enough to need them
+    Standard,
+    /// CHR bank bit 4 disables the 8KiB of PRG RAM
+    Snrom,
+    /// NES 2.0 submapper 5, 32KiB of PRG ROM that is never banked
+    Serom,
+}
+
+/// Mapper 1, Nintendo's MMC1. Registers are loaded one bit at a time
+/// through a 5-bit serial shift register.
+#[derive(Debug)]
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: ChrMemory,
+    board: Board,
+    shift_register: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+    cycle: u64,
+    last_serial_write: Option<u64>,
+}
+
+impl Mmc1 {
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        let board = if cartridge.submapper == 5 {
+            Board::Serom
+        } else if cartridge.chr_rom.is_empty()
+            && cartridge.prg_rom.len() <= 0x40000
+            && cartridge.total_prg_ram_size() <= PRG_RAM_BANK_SIZE
+        {
+            Board::Snrom
+        } else {
+            Board::Standard
+        };
+
+        Mmc1 {
+            prg_ram: super::prg_ram(&cartridge),
+            battery: cartridge.battery,
+            chr: ChrMemory::from_cartridge(&cartridge),
+            prg_rom: cartridge.prg_rom,
+            board,
+            shift_register: SHIFT_RESET,
+            // Power on with the last PRG bank fixed at $C000 so the reset
+            // vector is always reachable
+            control: 0b0_1100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            cycle: 0,
+            last_serial_write: None,
+        }
+    }
+
+    fn load_register(&mut self, addr: u16, data: u8) {
+        // The serial port ignores a write on the cycle after another one, so
+        // read-modify-write instructions only shift in the first of their two
+        // writes. The bus counts cycles after each instruction, which puts
+        // both of those writes on the same cycle here.
+        let consecutive = self
+            .last_serial_write
+            .is_some_and(|cycle| self.cycle <= cycle + 1);
+        self.last_serial_write = Some(self.cycle);
+        if consecutive {
+            return;
+        }
+
+        if data & 0b1000_0000 != 0 {
+            self.shift_register = SHIFT_RESET;
+            self.control |= 0b0_1100;
+            return;
+        }
+
+        let full = self.shift_register & 1 != 0;
+        self.shift_register = (self.shift_register >> 1) | ((data & 1) << 4);
+        if full {
+            let value = self.shift_register;
+            match (addr >> 13) & 0b11 {
+                0 => self.control = value,
+                1 => self.chr_bank_0 = value,
+                2 => self.chr_bank_1 = value,
+                _ => self.prg_bank = value,
+            }
+            self.shift_register = SHIFT_RESET;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        // SUROM and SXROM use CHR bank bit 4 to pick a 256KiB half of PRG ROM
+        let outer = if self.prg_rom.len() > 0x40000 {
+            (self.chr_bank_0 & 0x10) as usize
+        } else {
+            0
+        };
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let (low, high) = match self.board {
+            Board::Serom => (0, 1),
+            _ => match (self.control >> 2) & 0b11 {
+                0 | 1 => (bank & !1, bank | 1),
+                2 => (0, bank),
+                _ => (bank, 0x0F),
+            },
+        };
+        let bank = outer | if addr < 0xC000 { low } else { high };
+        (bank * PRG_BANK_SIZE + (addr as usize & 0x3FFF)) % self.prg_rom.len()
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        let snrom_disabled = self.board == Board::Snrom && self.chr_bank_0 & 0x10 != 0;
+        !self.prg_ram.is_empty() && self.prg_bank & 0x10 == 0 && !snrom_disabled
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> usize {
+        // SOROM banks 16KiB of PRG RAM with bit 3, SXROM 32KiB with bits 2-3
+        let bank = match self.prg_ram.len() {
+            0x4000 => (self.chr_bank_0 >> 3) & 0b01,
+            len if len > 0x4000 => (self.chr_bank_0 >> 2) & 0b11,
+            _ => 0,
+        } as usize;
+        (bank * PRG_RAM_BANK_SIZE + (addr - PRG_RAM) as usize) % self.prg_ram.len()
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let addr = addr as usize & 0x1FFF;
+        if self.control & 0b1_0000 == 0 {
+            (self.chr_bank_0 & 0x1E) as usize * CHR_BANK_SIZE + addr
+        } else {
+            let bank = if addr < CHR_BANK_SIZE {
+                self.chr_bank_0
+            } else {
+                self.chr_bank_1
+            };
+            bank as usize * CHR_BANK_SIZE + (addr & 0x0FFF)
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled() => {
+                Some(self.prg_ram[self.prg_ram_offset(addr)])
+            }
+            PRG_ROM..=PRG_ROM_END => Some(self.prg_rom[self.prg_rom_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled() => {
+                let offset = self.prg_ram_offset(addr);
+                self.prg_ram[offset] = data;
+            }
+            PRG_ROM..=PRG_ROM_END => self.load_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr.write(offset, data);
+    }
+
+    fn cpu_cycle(&mut self) {
+        self.cycle += 1;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mapper, Mmc1};
+    use crate::{
+        bus::Bus,
+        cartridge::{Cartridge, Mirroring},
+        cpu::{StopReason, CPU},
+    };
+
+    // PRG bytes hold their 16KiB bank number, CHR bytes their 4KiB one
+    fn cartridge(prg_size: usize, chr_size: usize) -> Cartridge {
+        let prg_rom = (0..prg_size).map(|i| (i / 0x4000) as u8).collect();
+        let chr_rom = (0..chr_size).map(|i| (i / 0x1000) as u8).collect();
+        Cartridge::new(1, prg_rom, chr_rom)
+    }
+
+    // A write from an STA, with the cycles it takes afterwards
+    fn write(mmc1: &mut Mmc1, addr: u16, data: u8) {
+        mmc1.cpu_write(addr, data);
+        for _ in 0..4 {
+            mmc1.cpu_cycle();
+        }
+    }
+
+    fn serial_write(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            write(mmc1, addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_shift_register_loads_after_five_writes() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x40000, 0x20000));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(15));
+
+        for _ in 0..4 {
+            write(&mut mmc1, 0xE000, 1);
+        }
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        write(&mut mmc1, 0xE000, 0);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(15));
+
+        // A write with bit 7 set throws away the partial value
+        write(&mut mmc1, 0xE000, 1);
+        write(&mut mmc1, 0xE000, 0x80);
+        serial_write(&mut mmc1, 0xE000, 3);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(3));
+    }
+
+    #[test]
+    fn test_mmc1_ignores_second_write_of_rmw_instructions() {
+        let mut cartridge = cartridge(0x40000, 0x20000);
+        // INC $E000 five times from the fixed bank then BRK, with $01 at $E000
+        let bank = &mut cartridge.prg_rom[0x3C000..];
+        for i in 0..5 {
+            bank[i * 3..(i + 1) * 3].copy_from_slice(&[0xEE, 0x00, 0xE0]);
+        }
+        bank[15] = 0x00;
+        bank[0x2000] = 0x01;
+        bank[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        let mut cpu = CPU::from_cartridge(cartridge).unwrap();
+        assert_eq!(cpu.run().unwrap(), StopReason::Brk);
+
+        // Only the unchanged $01 is shifted in each time, not the $02 after
+        // it, which loads bank $1F
+        assert_eq!(cpu.bus.peek(0x8100), 15);
+    }
+
+    #[test]
+    fn test_mmc1_prg_bank_modes() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x40000, 0x20000));
+        serial_write(&mut mmc1, 0xE000, 5);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(5));
+        assert_eq!(mmc1.cpu_read(0xFFFF), Some(15));
+
+        // Fix the first bank at $8000
+        serial_write(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(5));
+
+        // 32KiB mode ignores the low bit of the bank
+        serial_write(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(4));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(5));
+    }
+
+    #[test]
+    fn test_mmc1_chr_banks_and_mirroring() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x20000, 0x20000));
+        serial_write(&mut mmc1, 0xA000, 3);
+        serial_write(&mut mmc1, 0xC000, 7);
+
+        // 8KiB mode uses only the first register, without its low bit
+        assert_eq!(mmc1.ppu_read(0x0000), 2);
+        assert_eq!(mmc1.ppu_read(0x1000), 3);
+
+        serial_write(&mut mmc1, 0x8000, 0b1_1110);
+        assert_eq!(mmc1.ppu_read(0x0000), 3);
+        assert_eq!(mmc1.ppu_read(0x1000), 7);
+        assert_eq!(mmc1.mirroring(), Mirroring::Vertical);
+
+        serial_write(&mut mmc1, 0x8000, 0b1_1101);
+        assert_eq!(mmc1.mirroring(), Mirroring::SingleScreenUpper);
+    }
+
+    #[test]
+    fn test_mmc1_prg_ram_enable() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x20000, 0x20000));
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+
+        serial_write(&mut mmc1, 0xE000, 0b1_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), None);
+        mmc1.cpu_write(0x6000, 0x00);
+
+        serial_write(&mut mmc1, 0xE000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_snrom_disables_prg_ram_through_chr_bank() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x40000, 0));
+        mmc1.cpu_write(0x6000, 0x42);
+
+        serial_write(&mut mmc1, 0xA000, 0b1_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), None);
+
+        serial_write(&mut mmc1, 0xA000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_surom_selects_outer_prg_bank() {
+        let mut mmc1 = Mmc1::from_cartridge(cartridge(0x80000, 0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(15));
+
+        serial_write(&mut mmc1, 0xA000, 0b1_0000);
+        serial_write(&mut mmc1, 0xE000, 2);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(18));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(31));
+
+        // SUROM keeps PRG RAM mapped, bit 4 is a PRG line on this board
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x42));
+    }
+
+    #[test]
+    fn test_sxrom_banks_prg_ram() {
+        let mut cartridge = cartridge(0x80000, 0);
+        cartridge.prg_ram_size = 0;
+        cartridge.prg_nvram_size = 0x8000;
+        cartridge.battery = true;
+        let mut mmc1 = Mmc1::from_cartridge(cartridge);
+
+        mmc1.cpu_write(0x6000, 0x11);
+        serial_write(&mut mmc1, 0xA000, 0b0_1000);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x00));
+        mmc1.cpu_write(0x6000, 0x22);
+
+        serial_write(&mut mmc1, 0xA000, 0b0_0000);
+        assert_eq!(mmc1.cpu_read(0x6000), Some(0x11));
+    }
+
+    #[test]
+    fn test_serom_never_banks_prg() {
+        let mut cartridge = cartridge(0x8000, 0x2000);
+        cartridge.submapper = 5;
+        let mut mmc1 = Mmc1::from_cartridge(cartridge);
+
+        serial_write(&mut mmc1, 0xE000, 1);
+        assert_eq!(mmc1.cpu_read(0x8000), Some(0));
+        assert_eq!(mmc1.cpu_read(0xC000), Some(1));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+
+// A12 has to stay low for this many CPU cycles before a rising edge counts,
+// which filters out the short dips between sprite pattern fetches
+const A12_FILTER_CYCLES: u8 = 3;
+
+/// How the scanline counter behaves when it reaches zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IrqRevision {
+    /// The NEC MMC3A only fires when the counter decrements to zero or is
+    /// reloaded by a write to $C001, so a latch of zero fires once
+    Mmc3A,
+    /// The Sharp MMC3B/MMC3C fire every time the counter is zero after a
+    /// clock, so a latch of zero fires on every scanline
+    #[default]
+    Mmc3C,
+}
+
+/// Mapper 4, Nintendo's MMC3. Eight bank registers, and a scanline counter
+/// clocked by rising edges on PPU A12 that can raise an IRQ.
+#[derive(Debug)]
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: ChrMemory,
+    four_screen: bool,
+    bank_select: u8,
+    registers: [u8; 8],
+    mirroring: Mirroring,
+    prg_ram_enabled: bool,
+    prg_ram_write_protect: bool,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+    a12_low_cycles: u8,
+    pub irq_revision: IrqRevision,
+}
+
+impl Mmc3 {
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        // NES 2.0 submapper 4 marks boards with the older MMC3A
+        let irq_revision = if cartridge.submapper == 4 {
+            IrqRevision::Mmc3A
+        } else {
+            IrqRevision::Mmc3C
+        };
+
+        Mmc3 {
+            prg_ram: super::prg_ram(&cartridge),
+            battery: cartridge.battery,
+            chr: ChrMemory::from_cartridge(&cartridge),
+            four_screen: cartridge.mirroring == Mirroring::FourScreen,
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+            bank_select: 0,
+            registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            prg_ram_enabled: true,
+            prg_ram_write_protect: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0,
+            irq_revision,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+        match (addr & 0xE000, even) {
+            (0x8000, true) => self.bank_select = data,
+            (0x8000, false) => self.registers[(self.bank_select & 0b111) as usize] = data,
+            (0xA000, true) => {
+                if !self.four_screen {
+                    self.mirroring = if data & 1 == 0 {
+                        Mirroring::Vertical
+                    } else {
+                        Mirroring::Horizontal
+                    };
+                }
+            }
+            (0xA000, false) => {
+                self.prg_ram_enabled = data & 0b1000_0000 != 0;
+                self.prg_ram_write_protect = data & 0b0100_0000 != 0;
+            }
+            (0xC000, true) => self.irq_latch = data,
+            (0xC000, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            _ => self.irq_enabled = true,
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        let reloaded = self.irq_reload;
+        let before = self.irq_counter;
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        let fire = match self.irq_revision {
+            IrqRevision::Mmc3A => before != 0 || reloaded,
+            IrqRevision::Mmc3C => true,
+        };
+        if self.irq_counter == 0 && self.irq_enabled && fire {
+            self.irq_pending = true;
+        }
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> usize {
+        let bank_count = self.prg_rom.len() / PRG_BANK_SIZE;
+        let second_last = bank_count.saturating_sub(2);
+        let swapped = self.bank_select & 0b0100_0000 != 0;
+        let bank = match (addr & 0xE000, swapped) {
+            (0x8000, false) | (0xC000, true) => self.registers[6] as usize & 0x3F,
+            (0x8000, true) | (0xC000, false) => second_last,
+            (0xA000, _) => self.registers[7] as usize & 0x3F,
+            _ => bank_count.saturating_sub(1),
+        };
+        (bank * PRG_BANK_SIZE + (addr as usize & 0x1FFF)) % self.prg_rom.len()
+    }
+
+    fn prg_ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() {
+            return None;
+        }
+        Some((addr - PRG_RAM) as usize % self.prg_ram.len())
+    }
+
+    fn chr_offset(&self, addr: u16) -> usize {
+        let mut addr = addr as usize & 0x1FFF;
+        // With A12 inversion the 2KiB banks move to $1000-$1FFF
+        if self.bank_select & 0b1000_0000 != 0 {
+            addr ^= 0x1000;
+        }
+        let bank = match addr / CHR_BANK_SIZE {
+            0 => self.registers[0] & 0xFE,
+            1 => self.registers[0] | 0x01,
+            2 => self.registers[1] & 0xFE,
+            3 => self.registers[1] | 0x01,
+            slot => self.registers[slot - 2],
+        };
+        bank as usize * CHR_BANK_SIZE + (addr % CHR_BANK_SIZE)
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled => {
+                self.prg_ram_offset(addr).map(|offset| self.prg_ram[offset])
+            }
+            PRG_ROM..=PRG_ROM_END => Some(self.prg_rom[self.prg_rom_offset(addr)]),
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            PRG_RAM..=PRG_RAM_END if self.prg_ram_enabled && !self.prg_ram_write_protect => {
+                if let Some(offset) = self.prg_ram_offset(addr) {
+                    self.prg_ram[offset] = data;
+                }
+            }
+            PRG_ROM..=PRG_ROM_END => self.write_register(addr, data),
+            _ => {}
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(self.chr_offset(addr))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        let offset = self.chr_offset(addr);
+        self.chr.write(offset, data);
+    }
+
+    fn ppu_address(&mut self, addr: u16) {
+        let a12 = addr & 0x1000 != 0;
+        if a12 && !self.a12 && self.a12_low_cycles >= A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn cpu_cycle(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IrqRevision, Mapper, Mmc3};
+    use crate::cartridge::{Cartridge, Mirroring};
+
+    // PRG bytes hold their 8KiB bank number, CHR bytes their 1KiB one
+    fn board() -> Mmc3 {
+        let prg_rom = (0..0x40000).map(|i| (i / 0x2000) as u8).collect();
+        let chr_rom = (0..0x40000).map(|i| (i / 0x400) as u8).collect();
+        Mmc3::from_cartridge(Cartridge::new(4, prg_rom, chr_rom))
+    }
+
+    // One scanline of rendering with backgrounds at $0000 and sprites at
+    // $1000, A12 rises once when the sprite fetches start
+    fn scanline(mmc3: &mut Mmc3) {
+        for _ in 0..85 {
+            mmc3.cpu_cycle();
+        }
+        mmc3.ppu_address(0x0000);
+        mmc3.ppu_address(0x1000);
+        mmc3.ppu_address(0x2000);
+        mmc3.ppu_address(0x1010);
+        mmc3.ppu_address(0x0000);
+    }
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mmc3 = board();
+        mmc3.cpu_write(0x8000, 6);
+        mmc3.cpu_write(0x8001, 3);
+        mmc3.cpu_write(0x8000, 7);
+        mmc3.cpu_write(0x8001, 9);
+        assert_eq!(mmc3.cpu_read(0x8000), Some(3));
+        assert_eq!(mmc3.cpu_read(0xA000), Some(9));
+        assert_eq!(mmc3.cpu_read(0xC000), Some(30));
+        assert_eq!(mmc3.cpu_read(0xE000), Some(31));
+
+        // PRG mode 1 swaps $8000 and $C000
+        mmc3.cpu_write(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.cpu_read(0x8000), Some(30));
+        assert_eq!(mmc3.cpu_read(0xC000), Some(3));
+    }
+
+    #[test]
+    fn test_mmc3_chr_banks_and_inversion() {
+        let mut mmc3 = board();
+        for (register, bank) in [(0, 9), (1, 20), (2, 40), (3, 41), (4, 42), (5, 43)] {
+            mmc3.cpu_write(0x8000, register);
+            mmc3.cpu_write(0x8001, bank);
+        }
+        assert_eq!(mmc3.ppu_read(0x0000), 8);
+        assert_eq!(mmc3.ppu_read(0x0400), 9);
+        assert_eq!(mmc3.ppu_read(0x0800), 20);
+        assert_eq!(mmc3.ppu_read(0x1C00), 43);
+
+        mmc3.cpu_write(0x8000, 0b1000_0000);
+        assert_eq!(mmc3.ppu_read(0x0000), 40);
+        assert_eq!(mmc3.ppu_read(0x1400), 9);
+    }
+
+    #[test]
+    fn test_mmc3_mirroring_and_prg_ram_protect() {
+        let mut mmc3 = board();
+        mmc3.cpu_write(0xA000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::Horizontal);
+        mmc3.cpu_write(0xA000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::Vertical);
+
+        mmc3.cpu_write(0x6000, 0x42);
+        mmc3.cpu_write(0xA001, 0b1100_0000);
+        mmc3.cpu_write(0x6000, 0x00);
+        assert_eq!(mmc3.cpu_read(0x6000), Some(0x42));
+
+        mmc3.cpu_write(0xA001, 0b0000_0000);
+        assert_eq!(mmc3.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut mmc3 = board();
+        mmc3.cpu_write(0xC000, 2);
+        mmc3.cpu_write(0xC001, 0);
+        mmc3.cpu_write(0xE001, 0);
+
+        // Reload to 2, then 1, then 0 fires
+        scanline(&mut mmc3);
+        scanline(&mut mmc3);
+        assert!(!mmc3.irq());
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+
+        mmc3.cpu_write(0xE000, 0);
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn test_mmc3_filters_short_a12_pulses() {
+        let mut mmc3 = board();
+        mmc3.cpu_write(0xC000, 0);
+        mmc3.cpu_write(0xE001, 0);
+
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+        mmc3.cpu_write(0xE000, 0);
+        mmc3.cpu_write(0xE001, 0);
+
+        // Back to back toggles without CPU cycles in between never count
+        for _ in 0..8 {
+            mmc3.ppu_address(0x0000);
+            mmc3.ppu_address(0x1000);
+        }
+        assert!(!mmc3.irq());
+    }
+
+    #[test]
+    fn test_mmc3a_fires_once_with_zero_latch() {
+        let mut mmc3 = board();
+        mmc3.cpu_write(0xC000, 0);
+        mmc3.cpu_write(0xE001, 0);
+
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+        mmc3.cpu_write(0xE000, 0);
+        mmc3.cpu_write(0xE001, 0);
+        scanline(&mut mmc3);
+        assert!(mmc3.irq());
+
+        let mut mmc3a = board();
+        mmc3a.irq_revision = IrqRevision::Mmc3A;
+        mmc3a.cpu_write(0xC000, 0);
+        mmc3a.cpu_write(0xC001, 0);
+        mmc3a.cpu_write(0xE001, 0);
+
+        scanline(&mut mmc3a);
+        assert!(mmc3a.irq());
+        mmc3a.cpu_write(0xE000, 0);
+        mmc3a.cpu_write(0xE001, 0);
+        scanline(&mut mmc3a);
+        assert!(!mmc3a.irq());
+    }
+}
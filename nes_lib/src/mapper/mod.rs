@@ -0,0 +1,138 @@
+use std::fmt::Debug;
+
+use crate::{
+    cartridge::{Cartridge, Mirroring, RomFormat},
+    error::EmulatorError,
+};
+
+pub mod axrom;
+pub mod cnrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+pub use axrom::Axrom;
+pub use cnrom::Cnrom;
+pub use mmc1::Mmc1;
+pub use mmc3::{IrqRevision, Mmc3};
+pub use nrom::Nrom;
+pub use uxrom::Uxrom;
+
+const TRAINER_ADDRESS: usize = 0x1000;
+
+/// A cartridge board as seen from the CPU and PPU buses. Mappers decode
+/// addresses into their ROM and RAM banks and own any bank switching
+/// registers on the board.
+pub trait Mapper: Debug {
+    /// Reads from the cartridge space at $4020-$FFFF. `None` means nothing
+    /// on the board answers and the data bus floats.
+    fn cpu_read(&self, addr: u16) -> Option<u8>;
+
+    /// Writes to $4020-$FFFF, which covers both PRG RAM and bank registers
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    /// Reads from the pattern tables at $0000-$1FFF
+    fn ppu_read(&mut self, addr: u16) -> u8;
+
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    /// The nametable layout, which some boards switch at runtime
+    fn mirroring(&self) -> Mirroring;
+
+    /// Called with every address the PPU puts on its bus, for boards that
+    /// watch the address lines
+    fn ppu_address(&mut self, _addr: u16) {}
+
+    /// Called once per CPU cycle
+    fn cpu_cycle(&mut self) {}
+
+    /// Level of the cartridge's IRQ line, true while it is pulled low
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Battery backed PRG RAM, which outlives the session in a .sav file
+    fn save_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+}
+
+/// Builds the mapper for a cartridge from the number in its header
+pub fn from_cartridge(cartridge: Cartridge) -> Result<Box<dyn Mapper>, EmulatorError> {
+    match cartridge.mapper {
+        0 => Ok(Box::new(Nrom::from_cartridge(cartridge))),
+        1 => Ok(Box::new(Mmc1::from_cartridge(cartridge))),
+        2 => Ok(Box::new(Uxrom::from_cartridge(cartridge))),
+        3 => Ok(Box::new(Cnrom::from_cartridge(cartridge))),
+        4 => Ok(Box::new(Mmc3::from_cartridge(cartridge))),
+        7 => Ok(Box::new(Axrom::from_cartridge(cartridge))),
+        mapper => Err(EmulatorError::UnsupportedMapper(mapper)),
+    }
+}
+
+/// Allocates the board's PRG RAM, with the trainer copied to $7000
+pub(crate) fn prg_ram(cartridge: &Cartridge) -> Vec<u8> {
+    let mut prg_ram = vec![0; cartridge.total_prg_ram_size()];
+    if let Some(trainer) = &cartridge.trainer {
+        if prg_ram.len() >= TRAINER_ADDRESS + trainer.len() {
+            prg_ram[TRAINER_ADDRESS..(TRAINER_ADDRESS + trainer.len())].copy_from_slice(trainer);
+        }
+    }
+    prg_ram
+}
+
+/// NES 2.0 submappers 1 and 2 of the discrete latch boards say whether the
+/// ROM fights the CPU during register writes, otherwise use the board default
+pub(crate) fn has_bus_conflicts(cartridge: &Cartridge, default: bool) -> bool {
+    match (cartridge.format, cartridge.submapper) {
+        (RomFormat::Nes2, 1) => false,
+        (RomFormat::Nes2, 2) => true,
+        _ => default,
+    }
+}
+
+/// CHR ROM, or CHR RAM for boards that ship without any
+#[derive(Debug)]
+pub(crate) struct ChrMemory {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl ChrMemory {
+    pub fn ram(size: usize) -> Self {
+        ChrMemory {
+            data: vec![0; size],
+            writable: true,
+        }
+    }
+
+    pub fn from_cartridge(cartridge: &Cartridge) -> Self {
+        if cartridge.chr_rom.is_empty() {
+            ChrMemory::ram(cartridge.chr_ram_size)
+        } else {
+            ChrMemory {
+                data: cartridge.chr_rom.clone(),
+                writable: false,
+            }
+        }
+    }
+
+    pub fn read(&self, offset: usize) -> u8 {
+        if self.data.is_empty() {
+            return 0;
+        }
+        self.data[offset % self.data.len()]
+    }
+
+    pub fn write(&mut self, offset: usize, data: u8) {
+        if self.writable && !self.data.is_empty() {
+            let len = self.data.len();
+            self.data[offset % len] = data;
+        }
+    }
+}
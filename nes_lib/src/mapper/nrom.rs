@@ -0,0 +1,132 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7FFF;
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+
+/// Mapper 0. No bank switching at all, NROM-128 carries 16KiB of PRG ROM
+/// mirrored into $C000-$FFFF and NROM-256 fills $8000-$FFFF with 32KiB.
+#[derive(Debug)]
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    prg_ram: Vec<u8>,
+    battery: bool,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    /// A board with just the given PRG ROM, 8KiB of PRG RAM and CHR RAM
+    pub fn new(prg_rom: Vec<u8>) -> Self {
+        Nrom {
+            prg_rom,
+            prg_ram: vec![0; 0x2000],
+            battery: false,
+            chr: ChrMemory::ram(0x2000),
+            mirroring: Mirroring::Horizontal,
+        }
+    }
+
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        Nrom {
+            prg_ram: super::prg_ram(&cartridge),
+            battery: cartridge.battery,
+            chr: ChrMemory::from_cartridge(&cartridge),
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            // Family BASIC only has 2KiB or 4KiB here, so mirror whatever exists
+            PRG_RAM..=PRG_RAM_END if !self.prg_ram.is_empty() => {
+                Some(self.prg_ram[(addr - PRG_RAM) as usize % self.prg_ram.len()])
+            }
+            PRG_ROM..=PRG_ROM_END if !self.prg_rom.is_empty() => {
+                Some(self.prg_rom[(addr - PRG_ROM) as usize % self.prg_rom.len()])
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let PRG_RAM..=PRG_RAM_END = addr {
+            if !self.prg_ram.is_empty() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - PRG_RAM) as usize % len] = data;
+            }
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        self.battery.then_some(self.prg_ram.as_slice())
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.battery.then_some(self.prg_ram.as_mut_slice())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mapper, Nrom};
+    use crate::cartridge::{Cartridge, Mirroring};
+
+    fn prg_rom(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i / 0x4000) as u8).collect()
+    }
+
+    #[test]
+    fn test_nrom_128_mirrors_prg_rom() {
+        let nrom = Nrom::new(prg_rom(0x4000));
+        assert_eq!(nrom.cpu_read(0x8000), Some(0));
+        assert_eq!(nrom.cpu_read(0xC000), Some(0));
+        assert_eq!(nrom.cpu_read(0x5000), None);
+    }
+
+    #[test]
+    fn test_nrom_256_maps_both_banks() {
+        let mut cartridge = Cartridge::new(0, prg_rom(0x8000), vec![0x42; 0x2000]);
+        cartridge.mirroring = Mirroring::Vertical;
+
+        let mut nrom = Nrom::from_cartridge(cartridge);
+        assert_eq!(nrom.cpu_read(0xBFFF), Some(0));
+        assert_eq!(nrom.cpu_read(0xC000), Some(1));
+        assert_eq!(nrom.mirroring(), Mirroring::Vertical);
+
+        // CHR ROM ignores writes
+        nrom.ppu_write(0x0010, 0x00);
+        assert_eq!(nrom.ppu_read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_nrom_prg_and_chr_ram_are_writable() {
+        let mut nrom = Nrom::new(prg_rom(0x4000));
+        nrom.cpu_write(0x6123, 0x99);
+        assert_eq!(nrom.cpu_read(0x6123), Some(0x99));
+
+        nrom.cpu_write(0x8000, 0x99);
+        assert_eq!(nrom.cpu_read(0x8000), Some(0));
+
+        nrom.ppu_write(0x1FFF, 0x55);
+        assert_eq!(nrom.ppu_read(0x1FFF), 0x55);
+    }
+}
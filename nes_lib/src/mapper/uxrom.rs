@@ -0,0 +1,129 @@
+use crate::cartridge::{Cartridge, Mirroring};
+
+use super::{ChrMemory, Mapper};
+
+const PRG_ROM: u16 = 0x8000;
+const PRG_ROM_END: u16 = 0xFFFF;
+const PRG_BANK_SIZE: usize = 0x4000;
+
+/// Mapper 2. A latch selects the 16KiB bank at $8000 while the last bank
+/// stays fixed at $C000.
+#[derive(Debug)]
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    chr: ChrMemory,
+    mirroring: Mirroring,
+    bank: u8,
+    /// The ROM drives the data bus during latch writes, so the latched value
+    /// is ANDed with the byte stored at the written address
+    pub bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn from_cartridge(cartridge: Cartridge) -> Self {
+        Uxrom {
+            bus_conflicts: super::has_bus_conflicts(&cartridge, true),
+            chr: ChrMemory::from_cartridge(&cartridge),
+            mirroring: cartridge.mirroring,
+            prg_rom: cartridge.prg_rom,
+            bank: 0,
+        }
+    }
+
+    // NES 2.0 allows PRG ROM smaller than a bank, which then mirrors
+    fn last_bank(&self) -> usize {
+        (self.prg_rom.len() / PRG_BANK_SIZE).saturating_sub(1)
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&self, addr: u16) -> Option<u8> {
+        let bank = match addr {
+            PRG_ROM..=0xBFFF => self.bank as usize,
+            0xC000..=PRG_ROM_END => self.last_bank(),
+            _ => return None,
+        };
+        let offset = bank * PRG_BANK_SIZE + (addr as usize & 0x3FFF);
+        Some(self.prg_rom[offset % self.prg_rom.len()])
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if addr >= PRG_ROM {
+            self.bank = if self.bus_conflicts {
+                data & self.cpu_read(addr).unwrap_or(0xFF)
+            } else {
+                data
+            };
+        }
+    }
+
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.chr.read(addr as usize)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        self.chr.write(addr as usize, data);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Mapper, Uxrom};
+    use crate::cartridge::Cartridge;
+
+    fn uxrom(prg_rom: Vec<u8>) -> Uxrom {
+        Uxrom::from_cartridge(Cartridge::new(2, prg_rom, vec![]))
+    }
+
+    #[test]
+    fn test_uxrom_switches_low_bank_only() {
+        let mut uxrom = uxrom((0..0x20000).map(|i| (i / 0x4000) as u8).collect());
+        uxrom.bus_conflicts = false;
+
+        assert_eq!(uxrom.cpu_read(0x8000), Some(0));
+        assert_eq!(uxrom.cpu_read(0xC000), Some(7));
+
+        uxrom.cpu_write(0x8000, 5);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(5));
+        assert_eq!(uxrom.cpu_read(0xFFFF), Some(7));
+        assert_eq!(uxrom.cpu_read(0x6000), None);
+    }
+
+    #[test]
+    fn test_uxrom_bus_conflicts() {
+        let mut prg_rom: Vec<u8> = (0..0x20000).map(|i| (i / 0x4000) as u8).collect();
+        prg_rom[0x1C000] = 0x03;
+        let mut uxrom = uxrom(prg_rom);
+        assert!(uxrom.bus_conflicts);
+
+        // 0x06 & 0x03 latches bank 2
+        uxrom.cpu_write(0xC000, 0x06);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(2));
+
+        uxrom.bus_conflicts = false;
+        uxrom.cpu_write(0xC000, 0x06);
+        assert_eq!(uxrom.cpu_read(0x8000), Some(6));
+    }
+
+    #[test]
+    fn test_uxrom_mirrors_prg_smaller_than_a_bank() {
+        let mut uxrom = uxrom((0..0x2000).map(|i| (i >> 8) as u8).collect());
+        uxrom.bus_conflicts = false;
+        assert_eq!(uxrom.cpu_read(0xC000), Some(0x00));
+        assert_eq!(uxrom.cpu_read(0xE100), Some(0x01));
+
+        uxrom.cpu_write(0x8000, 3);
+        assert_eq!(uxrom.cpu_read(0xBF00), Some(0x1F));
+    }
+
+    #[test]
+    fn test_uxrom_chr_ram() {
+        let mut uxrom = uxrom(vec![0; 0x8000]);
+        uxrom.ppu_write(0x0123, 0x77);
+        assert_eq!(uxrom.ppu_read(0x0123), 0x77);
+    }
+}
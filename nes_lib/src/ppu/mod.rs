@@ -0,0 +1,566 @@
+use bitflags::bitflags;
+
+use crate::{cartridge::Mirroring, mapper::Mapper};
+
+use self::render::Sprite;
+
+pub use self::palette::{Palette, BUILT_IN_PALETTES};
+
+mod palette;
+mod render;
+
+bitflags! {
+    /// PPUCTRL, $2000
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  V P H B S I N N
+    pub struct ControlRegister: u8 {
+        const NAMETABLE_LO            = 0b0000_0001;
+        const NAMETABLE_HI            = 0b0000_0010;
+        const VRAM_INCREMENT          = 0b0000_0100;
+        const SPRITE_PATTERN_ADDR     = 0b0000_1000;
+        const BACKGROUND_PATTERN_ADDR = 0b0001_0000;
+        const SPRITE_SIZE             = 0b0010_0000;
+        const MASTER_SLAVE_SELECT     = 0b0100_0000;
+        const GENERATE_NMI            = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    /// PPUMASK, $2001
+    ///
+    ///  7 6 5 4 3 2 1 0
+    ///  B G R s b M m G
+    pub struct MaskRegister: u8 {
+        const GREYSCALE            = 0b0000_0001;
+        const SHOW_BACKGROUND_LEFT = 0b0000_0010;
+        const SHOW_SPRITES_LEFT    = 0b0000_0100;
+        const SHOW_BACKGROUND      = 0b0000_1000;
+        const SHOW_SPRITES         = 0b0001_0000;
+        const EMPHASIZE_RED        = 0b0010_0000;
+        const EMPHASIZE_GREEN      = 0b0100_0000;
+        const EMPHASIZE_BLUE       = 0b1000_0000;
+    }
+}
+
+bitflags! {
+    /// PPUSTATUS, $2002. The low five bits are not driven and read back as
+    /// whatever is left on the PPU's data bus.
+    pub struct StatusRegister: u8 {
+        const SPRITE_OVERFLOW = 0b0010_0000;
+        const SPRITE_ZERO_HIT = 0b0100_0000;
+        const VBLANK_STARTED  = 0b1000_0000;
+    }
+}
+
+const PPUCTRL: u16 = 0x2000;
+const PPUMASK: u16 = 0x2001;
+const PPUSTATUS: u16 = 0x2002;
+const OAMADDR: u16 = 0x2003;
+const OAMDATA: u16 = 0x2004;
+const PPUSCROLL: u16 = 0x2005;
+const PPUADDR: u16 = 0x2006;
+const PPUDATA: u16 = 0x2007;
+
+const PATTERN_TABLES_END: u16 = 0x1FFF;
+const NAMETABLES: u16 = 0x2000;
+const NAMETABLES_END: u16 = 0x3EFF;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+const VBLANK_SCANLINE: u16 = 241;
+const PRE_RENDER_SCANLINE: u16 = 261;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+/// The 2C02 picture processing unit. The pattern tables live on the
+/// cartridge, so anything that touches PPU memory takes the mapper.
+#[derive(Debug)]
+pub struct PPU {
+    pub ctrl: ControlRegister,
+    pub mask: MaskRegister,
+    pub status: StatusRegister,
+    pub oam_addr: u8,
+    pub oam: [u8; 256],
+    /// 2KiB of nametable RAM on the console, plus the 2KiB that four screen
+    /// cartridges add
+    vram: [u8; 4096],
+    palette: [u8; 32],
+    /// Current VRAM address, 15 bits: yyy NN YYYYY XXXXX
+    v: u16,
+    /// Temporary VRAM address, the top left of the screen
+    t: u16,
+    /// Fine X scroll
+    x: u8,
+    /// First or second write toggle shared by $2005 and $2006
+    w: bool,
+    read_buffer: u8,
+    /// The PPU's own data bus latch, which write-only registers read back
+    io_latch: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+    nmi_interrupt: bool,
+    // Background fetch latches and the 16-bit shift registers they feed
+    next_tile: u8,
+    next_attribute: u8,
+    next_pattern_lo: u8,
+    next_pattern_hi: u8,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attribute_lo: u16,
+    attribute_hi: u16,
+    /// Up to eight sprites found for the next scanline
+    sprites: Vec<Sprite>,
+    /// Palette index of each pixel with the emphasis bits above it, the same
+    /// layout as a 512 entry palette file
+    pixels: Vec<u16>,
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PPU {
+    pub fn new() -> Self {
+        PPU {
+            ctrl: ControlRegister::empty(),
+            mask: MaskRegister::empty(),
+            status: StatusRegister::empty(),
+            oam_addr: 0,
+            oam: [0; 256],
+            vram: [0; 4096],
+            palette: [0; 32],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            io_latch: 0,
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            nmi_interrupt: false,
+            next_tile: 0,
+            next_attribute: 0,
+            next_pattern_lo: 0,
+            next_pattern_hi: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            attribute_lo: 0,
+            attribute_hi: 0,
+            sprites: Vec::with_capacity(8),
+            pixels: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+
+    pub fn vram_address(&self) -> u16 {
+        self.v
+    }
+
+    pub fn temp_vram_address(&self) -> u16 {
+        self.t
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn write_toggle(&self) -> bool {
+        self.w
+    }
+
+    /// The screen as palette indices with emphasis bits. This is the buffer
+    /// the renderer draws into as it goes, so during the visible scanlines
+    /// it holds the lines drawn so far this frame over the rest of the last
+    /// one. It only holds one whole frame during VBlank, which is when hosts
+    /// should read it. The Zapper relies on seeing the beam's progress.
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    /// [`PPU::pixels`] as 256x240 packed RGB bytes, complete during VBlank
+    pub fn framebuffer(&self, palette: &Palette) -> Vec<u8> {
+        self.pixels
+            .iter()
+            .flat_map(|pixel| palette.rgb(*pixel))
+            .collect()
+    }
+
+    /// Takes the NMI raised at the start of VBlank, if there is one
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_interrupt)
+    }
+
+    fn vram_increment(&self) -> u16 {
+        if self.ctrl.contains(ControlRegister::VRAM_INCREMENT) {
+            32
+        } else {
+            1
+        }
+    }
+
+    pub fn read_register(&mut self, register: u16, mapper: &mut dyn Mapper) -> u8 {
+        let data = match register {
+            PPUSTATUS => {
+                let data = self.status.bits() | (self.io_latch & 0b0001_1111);
+                self.status.remove(StatusRegister::VBLANK_STARTED);
+                self.w = false;
+                data
+            }
+            OAMDATA => self.read_oam(),
+            PPUDATA => {
+                let addr = self.v & 0x3FFF;
+                let data = if addr >= 0x3F00 {
+                    // Palette reads skip the buffer, which picks up the
+                    // nametable byte underneath instead
+                    self.read_buffer = self.read_memory(addr - 0x1000, mapper);
+                    (self.read_memory(addr, mapper) & 0b0011_1111) | (self.io_latch & 0b1100_0000)
+                } else {
+                    let data = self.read_memory(addr, mapper);
+                    std::mem::replace(&mut self.read_buffer, data)
+                };
+                self.v = self.v.wrapping_add(self.vram_increment()) & 0x7FFF;
+                data
+            }
+            _ => self.io_latch,
+        };
+        self.io_latch = data;
+        data
+    }
+
+    /// Reads a register without clearing flags or moving the VRAM address
+    pub fn peek_register(&self, register: u16) -> u8 {
+        match register {
+            PPUSTATUS => self.status.bits() | (self.io_latch & 0b0001_1111),
+            OAMDATA => self.read_oam(),
+            PPUDATA => self.read_buffer,
+            _ => self.io_latch,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, data: u8, mapper: &mut dyn Mapper) {
+        self.io_latch = data;
+        match register {
+            PPUCTRL => {
+                let nmi_was_enabled = self.ctrl.contains(ControlRegister::GENERATE_NMI);
+                self.ctrl = ControlRegister::from_bits_truncate(data);
+                self.t = (self.t & !0x0C00) | (((data & 0b11) as u16) << 10);
+                // Turning NMIs on during VBlank fires one straight away
+                if !nmi_was_enabled
+                    && self.ctrl.contains(ControlRegister::GENERATE_NMI)
+                    && self.status.contains(StatusRegister::VBLANK_STARTED)
+                {
+                    self.nmi_interrupt = true;
+                }
+            }
+            PPUMASK => self.mask = MaskRegister::from_bits_truncate(data),
+            OAMADDR => self.oam_addr = data,
+            OAMDATA => {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            PPUSCROLL => {
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (data >> 3) as u16;
+                    self.x = data & 0b111;
+                } else {
+                    self.t = (self.t & !0x73E0)
+                        | (((data & 0b111) as u16) << 12)
+                        | (((data & 0b1111_1000) as u16) << 2);
+                }
+                self.w = !self.w;
+            }
+            PPUADDR => {
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | (((data & 0b0011_1111) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            PPUDATA => {
+                self.write_memory(self.v & 0x3FFF, data, mapper);
+                self.v = self.v.wrapping_add(self.vram_increment()) & 0x7FFF;
+            }
+            // PPUSTATUS is read only
+            _ => {}
+        }
+    }
+
+    fn read_oam(&self) -> u8 {
+        let data = self.oam[self.oam_addr as usize];
+        // Bits 2-4 of the sprite attribute byte don't exist
+        if self.oam_addr & 0b11 == 2 {
+            data & 0b1110_0011
+        } else {
+            data
+        }
+    }
+
+    fn read_memory(&mut self, addr: u16, mapper: &mut dyn Mapper) -> u8 {
+        mapper.ppu_address(addr);
+        match addr {
+            0..=PATTERN_TABLES_END => mapper.ppu_read(addr),
+            NAMETABLES..=NAMETABLES_END => {
+                self.vram[Self::mirror_nametable(addr, mapper.mirroring())]
+            }
+            _ => self.read_palette(addr),
+        }
+    }
+
+    fn write_memory(&mut self, addr: u16, data: u8, mapper: &mut dyn Mapper) {
+        mapper.ppu_address(addr);
+        match addr {
+            0..=PATTERN_TABLES_END => mapper.ppu_write(addr, data),
+            NAMETABLES..=NAMETABLES_END => {
+                self.vram[Self::mirror_nametable(addr, mapper.mirroring())] = data
+            }
+            _ => self.palette[Self::mirror_palette(addr)] = data & 0b0011_1111,
+        }
+    }
+
+    fn read_palette(&self, addr: u16) -> u8 {
+        let color = self.palette[Self::mirror_palette(addr)];
+        if self.mask.contains(MaskRegister::GREYSCALE) {
+            color & 0b0011_0000
+        } else {
+            color
+        }
+    }
+
+    /// Folds a nametable address into an offset in VRAM
+    pub fn mirror_nametable(addr: u16, mirroring: Mirroring) -> usize {
+        let addr = (addr as usize - NAMETABLES as usize) & 0x0FFF;
+        let table = addr / 0x400;
+        let table = match mirroring {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+        table * 0x400 + (addr % 0x400)
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C are the sprite palettes' copies of the
+    // background colors at $3F00/$3F04/$3F08/$3F0C
+    fn mirror_palette(addr: u16) -> usize {
+        let index = addr as usize & 0x1F;
+        if index >= 0x10 && index & 0b11 == 0 {
+            index - 0x10
+        } else {
+            index
+        }
+    }
+
+    pub fn rendering_enabled(&self) -> bool {
+        self.mask
+            .intersects(MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES)
+    }
+
+    /// Advances the PPU by one dot
+    pub fn tick(&mut self, mapper: &mut dyn Mapper) {
+        self.dot += 1;
+        // Odd frames skip the last dot of the pre-render line while rendering
+        if self.scanline == PRE_RENDER_SCANLINE
+            && self.dot == DOTS_PER_SCANLINE - 1
+            && self.frame % 2 == 1
+            && self.rendering_enabled()
+        {
+            self.dot += 1;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline > PRE_RENDER_SCANLINE {
+                self.scanline = 0;
+                self.frame += 1;
+            }
+        }
+
+        self.render_dot(mapper);
+
+        if self.dot == 1 {
+            match self.scanline {
+                VBLANK_SCANLINE => {
+                    self.status.insert(StatusRegister::VBLANK_STARTED);
+                    if self.ctrl.contains(ControlRegister::GENERATE_NMI) {
+                        self.nmi_interrupt = true;
+                    }
+                }
+                PRE_RENDER_SCANLINE => self.status.remove(
+                    StatusRegister::VBLANK_STARTED
+                        | StatusRegister::SPRITE_ZERO_HIT
+                        | StatusRegister::SPRITE_OVERFLOW,
+                ),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ControlRegister, StatusRegister, PPU};
+    use crate::{
+        cartridge::Mirroring,
+        mapper::{Mapper, Nrom},
+    };
+
+    fn set_address(ppu: &mut PPU, mapper: &mut dyn Mapper, addr: u16) {
+        ppu.write_register(0x2006, (addr >> 8) as u8, mapper);
+        ppu.write_register(0x2006, (addr & 0xFF) as u8, mapper);
+    }
+
+    #[test]
+    fn test_ppudata_reads_are_buffered() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        set_address(&mut ppu, &mut mapper, 0x2305);
+        ppu.write_register(0x2007, 0x66, &mut mapper);
+        ppu.write_register(0x2007, 0x77, &mut mapper);
+
+        set_address(&mut ppu, &mut mapper, 0x2305);
+        ppu.read_register(0x2007, &mut mapper);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x66);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x77);
+    }
+
+    #[test]
+    fn test_ppudata_increment_by_32() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        ppu.write_register(0x2000, ControlRegister::VRAM_INCREMENT.bits(), &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x2000);
+        ppu.write_register(0x2007, 0x11, &mut mapper);
+        assert_eq!(ppu.vram_address(), 0x2020);
+    }
+
+    #[test]
+    fn test_pattern_table_goes_through_mapper() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        set_address(&mut ppu, &mut mapper, 0x1234);
+        ppu.write_register(0x2007, 0x5A, &mut mapper);
+        assert_eq!(mapper.ppu_read(0x1234), 0x5A);
+    }
+
+    #[test]
+    fn test_palette_reads_bypass_buffer_and_mirror() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        set_address(&mut ppu, &mut mapper, 0x2F00);
+        ppu.write_register(0x2007, 0x99, &mut mapper);
+        set_address(&mut ppu, &mut mapper, 0x3F10);
+        ppu.write_register(0x2007, 0x2C, &mut mapper);
+
+        set_address(&mut ppu, &mut mapper, 0x3F00);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper), 0x2C);
+        // The buffer now holds the nametable byte under the palette
+        assert_eq!(ppu.peek_register(0x2007), 0x99);
+
+        set_address(&mut ppu, &mut mapper, 0x3FE0);
+        assert_eq!(ppu.read_register(0x2007, &mut mapper) & 0x3F, 0x2C);
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        assert_eq!(PPU::mirror_nametable(0x2400, Mirroring::Horizontal), 0x000);
+        assert_eq!(PPU::mirror_nametable(0x2800, Mirroring::Horizontal), 0x400);
+        assert_eq!(PPU::mirror_nametable(0x2400, Mirroring::Vertical), 0x400);
+        assert_eq!(PPU::mirror_nametable(0x2C05, Mirroring::Vertical), 0x405);
+        assert_eq!(
+            PPU::mirror_nametable(0x2C05, Mirroring::SingleScreenLower),
+            0x005
+        );
+        assert_eq!(PPU::mirror_nametable(0x2C05, Mirroring::FourScreen), 0xC05);
+        assert_eq!(PPU::mirror_nametable(0x3000, Mirroring::Vertical), 0x000);
+    }
+
+    #[test]
+    fn test_scroll_and_address_registers() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+
+        ppu.write_register(0x2000, 0b0000_0011, &mut mapper);
+        assert_eq!(ppu.temp_vram_address(), 0x0C00);
+
+        ppu.write_register(0x2005, 0b0111_1101, &mut mapper);
+        assert_eq!(ppu.temp_vram_address(), 0x0C0F);
+        assert_eq!(ppu.fine_x(), 0b101);
+        assert!(ppu.write_toggle());
+
+        ppu.write_register(0x2005, 0b0101_1110, &mut mapper);
+        assert_eq!(ppu.temp_vram_address(), 0x6D6F);
+        assert!(!ppu.write_toggle());
+
+        ppu.write_register(0x2006, 0b0011_1101, &mut mapper);
+        ppu.write_register(0x2006, 0b1111_0000, &mut mapper);
+        assert_eq!(ppu.temp_vram_address(), 0x3DF0);
+        assert_eq!(ppu.vram_address(), 0x3DF0);
+    }
+
+    #[test]
+    fn test_status_read_clears_vblank_and_toggle() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2005, 0x00, &mut mapper);
+
+        let status = ppu.read_register(0x2002, &mut mapper);
+        assert_eq!(status & 0b1000_0000, 0b1000_0000);
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+        assert!(!ppu.write_toggle());
+    }
+
+    #[test]
+    fn test_oam_data() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        ppu.write_register(0x2003, 0x10, &mut mapper);
+        ppu.write_register(0x2004, 0x12, &mut mapper);
+        ppu.write_register(0x2004, 0x34, &mut mapper);
+        ppu.write_register(0x2004, 0xFF, &mut mapper);
+
+        ppu.write_register(0x2003, 0x11, &mut mapper);
+        assert_eq!(ppu.read_register(0x2004, &mut mapper), 0x34);
+        ppu.write_register(0x2003, 0x12, &mut mapper);
+        assert_eq!(ppu.read_register(0x2004, &mut mapper), 0xE3);
+    }
+
+    #[test]
+    fn test_vblank_raises_nmi() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        ppu.write_register(0x2000, ControlRegister::GENERATE_NMI.bits(), &mut mapper);
+
+        while !ppu.status.contains(StatusRegister::VBLANK_STARTED) {
+            ppu.tick(&mut mapper);
+        }
+        assert_eq!((ppu.scanline, ppu.dot), (241, 1));
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+
+        while ppu.scanline != 261 || ppu.dot != 1 {
+            ppu.tick(&mut mapper);
+        }
+        assert!(!ppu.status.contains(StatusRegister::VBLANK_STARTED));
+    }
+
+    #[test]
+    fn test_enabling_nmi_during_vblank_fires() {
+        let mut ppu = PPU::new();
+        let mut mapper = Nrom::new(vec![0; 0x4000]);
+        ppu.status.insert(StatusRegister::VBLANK_STARTED);
+        ppu.write_register(0x2000, ControlRegister::GENERATE_NMI.bits(), &mut mapper);
+        assert!(ppu.poll_nmi());
+
+        // Writing it again while already enabled does not
+        ppu.write_register(0x2000, ControlRegister::GENERATE_NMI.bits(), &mut mapper);
+        assert!(!ppu.poll_nmi());
+    }
+}
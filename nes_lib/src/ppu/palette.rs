@@ -0,0 +1,189 @@
+use std::path::Path;
+
+use crate::error::EmulatorError;
+
+// The 2C02's 64 colors in RGB
+#[rustfmt::skip]
+const NTSC_COLORS: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96),
+    (0xA1, 0x00, 0x5E), (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00),
+    (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E),
+    (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05), (0x05, 0x05, 0x05),
+    (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00),
+    (0xC4, 0x62, 0x00), (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55),
+    (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21), (0x09, 0x09, 0x09), (0x09, 0x09, 0x09),
+    (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF), (0xD4, 0x80, 0xFF),
+    (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4),
+    (0x05, 0xFB, 0xFF), (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D),
+    (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF), (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB),
+    (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), (0xFF, 0xEF, 0xA6),
+    (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+// The RGB PPUs in arcade and PlayChoice boards (2C03, 2C05) output each
+// channel as one of 8 levels, written here as octal digits
+#[rustfmt::skip]
+const RGB_PPU_LEVELS: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+/// Names accepted by `Palette::built_in`
+pub const BUILT_IN_PALETTES: [&str; 2] = ["ntsc", "rgb"];
+
+// How much an emphasis bit darkens the channels it doesn't emphasize
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+const COLORS: usize = 64;
+// Every color under each of the eight combinations of emphasis bits
+const EMPHASIS_COLORS: usize = COLORS * 8;
+
+/// Maps the PPU's pixels, a 6-bit color index with the three PPUMASK
+/// emphasis bits (red, green, blue) above it, to RGB
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    colors: Vec<[u8; 3]>,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::ntsc()
+    }
+}
+
+impl Palette {
+    /// The built in palette for the NTSC 2C02
+    pub fn ntsc() -> Self {
+        Palette::with_emphasis(NTSC_COLORS.iter().map(|&(r, g, b)| [r, g, b]).collect())
+    }
+
+    /// The palette of the RGB PPUs used in Vs. System and PlayChoice-10
+    /// boards. Emphasis is approximated the same way as for 64 color .pal
+    /// files.
+    pub fn rgb_ppu() -> Self {
+        let level = |octal: u16| ((octal & 0o7) * 255 / 7) as u8;
+        Palette::with_emphasis(
+            RGB_PPU_LEVELS
+                .iter()
+                .map(|&color| [level(color >> 6), level(color >> 3), level(color)])
+                .collect(),
+        )
+    }
+
+    /// Looks up one of the `BUILT_IN_PALETTES` by name
+    pub fn built_in(name: &str) -> Option<Self> {
+        match name {
+            "ntsc" => Some(Palette::ntsc()),
+            "rgb" => Some(Palette::rgb_ppu()),
+            _ => None,
+        }
+    }
+
+    /// Reads a .pal file, see `from_bytes`
+    pub fn from_file(path: &Path) -> Result<Self, EmulatorError> {
+        Palette::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Loads RGB triples in the .pal layout. 192 bytes hold the 64 base
+    /// colors and emphasis is approximated from them, 1536 bytes also hold
+    /// the colors for every emphasis combination in order.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, EmulatorError> {
+        let colors = data
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        match data.len() {
+            len if len == COLORS * 3 => Ok(Palette::with_emphasis(colors)),
+            len if len == EMPHASIS_COLORS * 3 => Ok(Palette { colors }),
+            len => Err(EmulatorError::InvalidPalette(len)),
+        }
+    }
+
+    // Fills in the emphasized colors by darkening the channels each set
+    // emphasis bit doesn't cover
+    fn with_emphasis(base: Vec<[u8; 3]>) -> Self {
+        let colors = (0..EMPHASIS_COLORS)
+            .map(|pixel| {
+                let emphasis = pixel / COLORS;
+                let mut rgb = base[pixel % COLORS].map(|value| value as f32);
+                for bit in (0..3).filter(|bit| emphasis & (1 << bit) != 0) {
+                    for (channel, value) in rgb.iter_mut().enumerate() {
+                        if channel != bit {
+                            *value *= EMPHASIS_ATTENUATION;
+                        }
+                    }
+                }
+                rgb.map(|value| value as u8)
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        self.colors[pixel as usize % EMPHASIS_COLORS]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Palette, BUILT_IN_PALETTES};
+    use crate::error::EmulatorError;
+
+    #[test]
+    fn test_ntsc_palette_emphasis() {
+        let palette = Palette::ntsc();
+        assert_eq!(palette.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+        // Red emphasis leaves red alone and darkens green and blue
+        assert_eq!(palette.rgb(0x070), [0xFF, 0xBE, 0xBE]);
+        // All three darken everything
+        assert_eq!(palette.rgb(0x1F0), [0x8D, 0x8D, 0x8D]);
+    }
+
+    #[test]
+    fn test_built_in_palettes_by_name() {
+        for name in BUILT_IN_PALETTES {
+            assert!(Palette::built_in(name).is_some());
+        }
+        assert_eq!(Palette::built_in("ntsc"), Some(Palette::ntsc()));
+        assert_eq!(Palette::built_in("pal"), None);
+
+        let rgb = Palette::built_in("rgb").unwrap();
+        assert_eq!(rgb.rgb(0x00), [0x6D, 0x6D, 0x6D]);
+        assert_eq!(rgb.rgb(0x16), [0xFF, 0x00, 0x00]);
+        assert_eq!(rgb.rgb(0x30), [0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_64_color_pal_file_gets_emphasis() {
+        let data: Vec<u8> = (0..192).map(|i| i as u8).collect();
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.rgb(0x01), [3, 4, 5]);
+        assert_eq!(palette.rgb(0x3F), [189, 190, 191]);
+        assert_eq!(palette.rgb(0x13F), [140, 141, 191]);
+    }
+
+    #[test]
+    fn test_512_color_pal_file_is_used_as_is() {
+        let data: Vec<u8> = (0..1536).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::from_bytes(&data).unwrap();
+        assert_eq!(palette.rgb(0x01), [1, 1, 1]);
+        assert_eq!(palette.rgb(0x141), [0x41, 0x41, 0x41]);
+    }
+
+    #[test]
+    fn test_rejects_other_sizes() {
+        assert!(matches!(
+            Palette::from_bytes(&[0; 100]),
+            Err(EmulatorError::InvalidPalette(100))
+        ));
+    }
+}
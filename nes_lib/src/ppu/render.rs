@@ -0,0 +1,516 @@
+use crate::mapper::Mapper;
+
+use super::{
+    ControlRegister, MaskRegister, StatusRegister, PPU, PRE_RENDER_SCANLINE, SCREEN_HEIGHT,
+    SCREEN_WIDTH,
+};
+
+const MAX_SPRITES_PER_LINE: usize = 8;
+
+/// A sprite picked during evaluation, with its row of pattern data once the
+/// fetches for the line are done
+#[derive(Debug, Clone, Copy)]
+pub struct Sprite {
+    x: u8,
+    tile: u8,
+    attribute: u8,
+    row: u8,
+    sprite_zero: bool,
+    pattern_lo: u8,
+    pattern_hi: u8,
+}
+
+impl Sprite {
+    const PALETTE: u8 = 0b0000_0011;
+    const BEHIND_BACKGROUND: u8 = 0b0010_0000;
+    const FLIP_HORIZONTAL: u8 = 0b0100_0000;
+    const FLIP_VERTICAL: u8 = 0b1000_0000;
+
+    const EMPTY: Sprite = Sprite {
+        x: 0xFF,
+        tile: 0xFF,
+        attribute: 0,
+        row: 0,
+        sprite_zero: false,
+        pattern_lo: 0,
+        pattern_hi: 0,
+    };
+
+    fn pixel(&self, x: u16) -> u8 {
+        let column = x.wrapping_sub(self.x as u16);
+        if column >= 8 {
+            return 0;
+        }
+        let bit = 7 - column;
+        (((self.pattern_hi >> bit) & 1) << 1) | ((self.pattern_lo >> bit) & 1)
+    }
+}
+
+impl PPU {
+    // Runs the rendering work for the current dot
+    pub(super) fn render_dot(&mut self, mapper: &mut dyn Mapper) {
+        let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+        let pre_render = self.scanline == PRE_RENDER_SCANLINE;
+        let dot = self.dot;
+
+        if !self.rendering_enabled() {
+            if visible && (1..=256).contains(&dot) {
+                self.put_pixel(0);
+            }
+            return;
+        }
+        if !visible && !pre_render {
+            return;
+        }
+
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_background();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_background_shifters();
+                    self.fetch_nametable_byte(mapper);
+                }
+                2 => self.fetch_attribute_byte(mapper),
+                4 => self.next_pattern_lo = self.fetch_background_pattern(0, mapper),
+                6 => self.next_pattern_hi = self.fetch_background_pattern(8, mapper),
+                7 => self.increment_coarse_x(),
+                _ => {}
+            }
+        }
+        match dot {
+            256 => self.increment_y(),
+            257 => {
+                self.load_background_shifters();
+                self.copy_horizontal_position();
+                self.evaluate_sprites(visible);
+            }
+            280..=304 if pre_render => self.copy_vertical_position(),
+            // Unused nametable fetches at the end of the line
+            338 | 340 => self.fetch_nametable_byte(mapper),
+            _ => {}
+        }
+        if (257..=320).contains(&dot) {
+            self.fetch_sprite(dot - 257, mapper);
+        }
+
+        if visible && (1..=256).contains(&dot) {
+            self.render_pixel(dot - 1);
+        }
+    }
+
+    fn put_pixel(&mut self, color: u8) {
+        let x = self.dot as usize - 1;
+        let emphasis = (self.mask.bits() >> 5) as u16;
+        let color = self.read_palette(0x3F00 + color as u16) as u16;
+        self.pixels[self.scanline as usize * SCREEN_WIDTH + x] = (emphasis << 6) | color;
+    }
+
+    fn render_pixel(&mut self, x: u16) {
+        let (background, background_palette) = self.background_pixel(x);
+
+        let mut sprite = None;
+        if self.mask.contains(MaskRegister::SHOW_SPRITES)
+            && (x >= 8 || self.mask.contains(MaskRegister::SHOW_SPRITES_LEFT))
+        {
+            // Sprite 0 hits even when another sprite is drawn over it
+            if let Some(first) = self.sprites.first() {
+                if first.sprite_zero && first.pixel(x) != 0 && background != 0 && x != 255 {
+                    self.status.insert(StatusRegister::SPRITE_ZERO_HIT);
+                }
+            }
+            sprite = self
+                .sprites
+                .iter()
+                .map(|sprite| (sprite.pixel(x), sprite))
+                .find(|(pixel, _)| *pixel != 0)
+                .map(|(pixel, sprite)| (pixel, *sprite));
+        }
+
+        let color = match (background, sprite) {
+            (0, None) => 0,
+            (0, Some((pixel, sprite))) => {
+                0x10 | ((sprite.attribute & Sprite::PALETTE) << 2) | pixel
+            }
+            (_, None) => (background_palette << 2) | background,
+            (_, Some((pixel, sprite))) => {
+                if sprite.attribute & Sprite::BEHIND_BACKGROUND != 0 {
+                    (background_palette << 2) | background
+                } else {
+                    0x10 | ((sprite.attribute & Sprite::PALETTE) << 2) | pixel
+                }
+            }
+        };
+        self.put_pixel(color);
+    }
+
+    // The two bit pattern value and the palette of the background at `x`
+    fn background_pixel(&self, x: u16) -> (u8, u8) {
+        if !self.mask.contains(MaskRegister::SHOW_BACKGROUND)
+            || (x < 8 && !self.mask.contains(MaskRegister::SHOW_BACKGROUND_LEFT))
+        {
+            return (0, 0);
+        }
+        let bit = 0x8000 >> self.x;
+        let select = |shifter: u16| (shifter & bit != 0) as u8;
+        (
+            (select(self.pattern_hi) << 1) | select(self.pattern_lo),
+            (select(self.attribute_hi) << 1) | select(self.attribute_lo),
+        )
+    }
+
+    fn shift_background(&mut self) {
+        self.pattern_lo <<= 1;
+        self.pattern_hi <<= 1;
+        self.attribute_lo <<= 1;
+        self.attribute_hi <<= 1;
+    }
+
+    fn load_background_shifters(&mut self) {
+        self.pattern_lo = (self.pattern_lo & 0xFF00) | self.next_pattern_lo as u16;
+        self.pattern_hi = (self.pattern_hi & 0xFF00) | self.next_pattern_hi as u16;
+        let spread = |bit: u8| {
+            if self.next_attribute & bit != 0 {
+                0xFF
+            } else {
+                0x00
+            }
+        };
+        self.attribute_lo = (self.attribute_lo & 0xFF00) | spread(0b01);
+        self.attribute_hi = (self.attribute_hi & 0xFF00) | spread(0b10);
+    }
+
+    fn fetch_nametable_byte(&mut self, mapper: &mut dyn Mapper) {
+        self.next_tile = self.read_memory(0x2000 | (self.v & 0x0FFF), mapper);
+    }
+
+    fn fetch_attribute_byte(&mut self, mapper: &mut dyn Mapper) {
+        let v = self.v;
+        let addr = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+        // Each attribute byte covers a 4x4 tile area in 2x2 tile quadrants
+        let shift = ((v >> 4) & 0b100) | (v & 0b10);
+        self.next_attribute = (self.read_memory(addr, mapper) >> shift) & 0b11;
+    }
+
+    fn fetch_background_pattern(&mut self, plane: u16, mapper: &mut dyn Mapper) -> u8 {
+        let table = if self.ctrl.contains(ControlRegister::BACKGROUND_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0x0000
+        };
+        let fine_y = (self.v >> 12) & 0b111;
+        self.read_memory(table + self.next_tile as u16 * 16 + plane + fine_y, mapper)
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            // Wrap into the horizontally adjacent nametable
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            // Wrap into the vertically adjacent nametable
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Rows 30 and 31 are attribute data, which wrap without switching
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal_position(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn copy_vertical_position(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl.contains(ControlRegister::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        }
+    }
+
+    // Finds the first eight sprites on the next line. This skips the
+    // hardware's buggy overflow search and just checks for a ninth sprite.
+    fn evaluate_sprites(&mut self, visible: bool) {
+        self.sprites.clear();
+        if !visible {
+            return;
+        }
+        let height = self.sprite_height();
+        for (index, entry) in self.oam.chunks_exact(4).enumerate() {
+            let row = self.scanline.wrapping_sub(entry[0] as u16);
+            if row >= height {
+                continue;
+            }
+            if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                self.status.insert(StatusRegister::SPRITE_OVERFLOW);
+                break;
+            }
+            self.sprites.push(Sprite {
+                x: entry[3],
+                tile: entry[1],
+                attribute: entry[2],
+                row: row as u8,
+                sprite_zero: index == 0,
+                pattern_lo: 0,
+                pattern_hi: 0,
+            });
+        }
+    }
+
+    // Each of the eight sprite slots takes eight dots. Empty slots still
+    // fetch tile $FF, which mappers watching A12 rely on.
+    fn fetch_sprite(&mut self, offset: u16, mapper: &mut dyn Mapper) {
+        let slot = (offset / 8) as usize;
+        let plane = match offset % 8 {
+            0 | 2 => return self.fetch_nametable_byte(mapper),
+            4 => 0,
+            6 => 8,
+            _ => return,
+        };
+        let sprite = self.sprites.get(slot).copied().unwrap_or(Sprite::EMPTY);
+        let mut data = self.read_memory(self.sprite_pattern_addr(&sprite) + plane, mapper);
+        if sprite.attribute & Sprite::FLIP_HORIZONTAL != 0 {
+            data = data.reverse_bits();
+        }
+        if let Some(sprite) = self.sprites.get_mut(slot) {
+            if plane == 0 {
+                sprite.pattern_lo = data;
+            } else {
+                sprite.pattern_hi = data;
+            }
+        }
+    }
+
+    fn sprite_pattern_addr(&self, sprite: &Sprite) -> u16 {
+        let height = self.sprite_height();
+        let mut row = sprite.row as u16;
+        if sprite.attribute & Sprite::FLIP_VERTICAL != 0 {
+            row = height - 1 - row;
+        }
+        if height == 16 {
+            // Bit 0 of the tile picks the pattern table, the bottom half of
+            // the sprite is the next tile
+            let table = (sprite.tile as u16 & 1) * 0x1000;
+            let tile = (sprite.tile & 0xFE) as u16 + row / 8;
+            table + tile * 16 + (row % 8)
+        } else {
+            let table = if self.ctrl.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+                0x1000
+            } else {
+                0x0000
+            };
+            table + sprite.tile as u16 * 16 + row
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        cartridge::Cartridge,
+        mapper::{Mapper, Nrom},
+        ppu::{ControlRegister, MaskRegister, Palette, StatusRegister, PPU},
+    };
+
+    // Tile 1 is solid color 1, tile 2 solid color 3, tile 3 is color 1 on
+    // its top row only
+    fn mapper() -> Nrom {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x10..0x18].fill(0xFF);
+        chr_rom[0x20..0x30].fill(0xFF);
+        chr_rom[0x30] = 0xFF;
+        chr_rom[0x1010..0x1018].fill(0xFF);
+        Nrom::from_cartridge(Cartridge::new(0, vec![0; 0x4000], chr_rom))
+    }
+
+    fn write(ppu: &mut PPU, mapper: &mut dyn Mapper, addr: u16, data: &[u8]) {
+        ppu.write_register(0x2006, (addr >> 8) as u8, mapper);
+        ppu.write_register(0x2006, (addr & 0xFF) as u8, mapper);
+        for byte in data {
+            ppu.write_register(0x2007, *byte, mapper);
+        }
+    }
+
+    fn setup(mapper: &mut dyn Mapper) -> PPU {
+        let mut ppu = PPU::new();
+        // Backdrop, background palette 0 and sprite palette 0
+        write(&mut ppu, mapper, 0x3F00, &[0x0F, 0x30, 0x16, 0x2A]);
+        write(&mut ppu, mapper, 0x3F11, &[0x12, 0x14, 0x11]);
+        write(&mut ppu, mapper, 0x3F15, &[0x27]);
+        // Park every sprite below the screen
+        ppu.oam.fill(0xFF);
+        ppu
+    }
+
+    fn render_frame(ppu: &mut PPU, mapper: &mut dyn Mapper) {
+        ppu.write_register(0x2000, ppu.ctrl.bits(), mapper);
+        ppu.write_register(0x2005, 0, mapper);
+        ppu.write_register(0x2005, 0, mapper);
+        let frame = ppu.frame;
+        while ppu.frame < frame + 2 {
+            ppu.tick(mapper);
+        }
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u16 {
+        ppu.pixels()[y * 256 + x]
+    }
+
+    #[test]
+    fn test_renders_background_tiles() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[1, 0, 2]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_BACKGROUND_LEFT;
+        render_frame(&mut ppu, &mut mapper);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0x30);
+        assert_eq!(pixel(&ppu, 7, 7), 0x30);
+        assert_eq!(pixel(&ppu, 8, 0), 0x0F);
+        assert_eq!(pixel(&ppu, 16, 0), 0x2A);
+        assert_eq!(pixel(&ppu, 0, 8), 0x0F);
+        assert_eq!(&ppu.framebuffer(&Palette::ntsc())[..3], &[0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_fine_scroll() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[1]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_BACKGROUND_LEFT;
+        render_frame(&mut ppu, &mut mapper);
+
+        ppu.write_register(0x2005, 3, &mut mapper);
+        ppu.write_register(0x2005, 2, &mut mapper);
+        // The vertical scroll is only picked up on the pre-render line
+        let frame = ppu.frame;
+        while ppu.frame < frame + 2 {
+            ppu.tick(&mut mapper);
+        }
+        assert_eq!(pixel(&ppu, 4, 0), 0x30);
+        assert_eq!(pixel(&ppu, 5, 0), 0x0F);
+        assert_eq!(pixel(&ppu, 0, 5), 0x30);
+        assert_eq!(pixel(&ppu, 0, 6), 0x0F);
+    }
+
+    #[test]
+    fn test_left_column_clipping() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[1, 1]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND;
+        render_frame(&mut ppu, &mut mapper);
+
+        assert_eq!(pixel(&ppu, 7, 0), 0x0F);
+        assert_eq!(pixel(&ppu, 8, 0), 0x30);
+    }
+
+    #[test]
+    fn test_sprites_and_priority() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[0, 0, 3]);
+        write(&mut ppu, &mut mapper, 0x20CC, &[1]);
+        // Sprite 0 below tile 3's opaque row, sprite 1 behind the background
+        // in palette 1, half over a solid tile
+        ppu.oam[..8].copy_from_slice(&[9, 1, 0, 20, 49, 1, 0b0010_0001, 100]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES;
+        render_frame(&mut ppu, &mut mapper);
+
+        // Sprite Y is one less than the first line it appears on
+        assert_eq!(pixel(&ppu, 20, 9), 0x0F);
+        assert_eq!(pixel(&ppu, 20, 10), 0x12);
+        assert_eq!(pixel(&ppu, 100, 50), 0x30);
+        assert_eq!(pixel(&ppu, 104, 50), 0x27);
+
+        while ppu.scanline != 100 {
+            ppu.tick(&mut mapper);
+        }
+        assert!(!ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit_on_opaque_background() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[0, 0, 1]);
+        ppu.oam[..4].copy_from_slice(&[3, 1, 0, 20]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND | MaskRegister::SHOW_SPRITES;
+        render_frame(&mut ppu, &mut mapper);
+        while ppu.scanline != 10 {
+            ppu.tick(&mut mapper);
+        }
+        assert!(ppu.status.contains(StatusRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_sprite_overflow_and_eight_per_line() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        for sprite in 0..9 {
+            ppu.oam[sprite * 4..sprite * 4 + 4].copy_from_slice(&[19, 1, 0, sprite as u8 * 10]);
+        }
+        ppu.oam[36..].fill(0xFF);
+        ppu.mask = MaskRegister::SHOW_SPRITES | MaskRegister::SHOW_SPRITES_LEFT;
+        render_frame(&mut ppu, &mut mapper);
+
+        assert_eq!(pixel(&ppu, 70, 20), 0x12);
+        assert_eq!(pixel(&ppu, 80, 20), 0x0F);
+        while ppu.scanline != 30 {
+            ppu.tick(&mut mapper);
+        }
+        assert!(ppu.status.contains(StatusRegister::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn test_8x16_sprites_use_next_tile() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        // Tile 2 from the $0000 table on top, tile 3 below it
+        ppu.oam[..4].copy_from_slice(&[49, 2, 0, 40]);
+        ppu.oam[4..].fill(0xFF);
+        ppu.ctrl = ControlRegister::SPRITE_SIZE;
+        ppu.mask = MaskRegister::SHOW_SPRITES;
+        render_frame(&mut ppu, &mut mapper);
+
+        assert_eq!(pixel(&ppu, 40, 50), 0x11);
+        assert_eq!(pixel(&ppu, 40, 57), 0x11);
+        assert_eq!(pixel(&ppu, 40, 58), 0x12);
+        assert_eq!(pixel(&ppu, 40, 59), 0x0F);
+    }
+
+    #[test]
+    fn test_emphasis_bits_darken_framebuffer() {
+        let mut mapper = mapper();
+        let mut ppu = setup(&mut mapper);
+        write(&mut ppu, &mut mapper, 0x2000, &[1]);
+        ppu.mask = MaskRegister::SHOW_BACKGROUND
+            | MaskRegister::SHOW_BACKGROUND_LEFT
+            | MaskRegister::EMPHASIZE_RED;
+        render_frame(&mut ppu, &mut mapper);
+
+        assert_eq!(pixel(&ppu, 0, 0), 0b001 << 6 | 0x30);
+        let rgb = &ppu.framebuffer(&Palette::ntsc())[..3];
+        assert_eq!(rgb[0], 0xFF);
+        assert!(rgb[1] < 0xFF && rgb[2] < 0xFF);
+    }
+}